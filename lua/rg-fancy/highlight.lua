@@ -7,6 +7,8 @@ local ns = api.nvim_create_namespace("NaughieRgFancyHl")
 local default_hl = {
     input_hint = { link = "Comment" },
     input_hint_notice = { link = "Comment" },
+    input_error = { link = "DiagnosticUnderlineError" },
+    input_error_msg = { link = "DiagnosticVirtualTextError" },
     path = { link = "Directory" },
     line_idx = { link = "LineNr" },
     cursor_line_idx = { link = "CursorLineNr" },
@@ -14,6 +16,10 @@ local default_hl = {
     count = { link = "Comment" },
     context = { link = "Comment" },
     matched = { link = "Search" },
+    matched_span = { link = "IncSearch" },
+    replaced = { link = "DiffAdd" },
+    replaced_span = { link = "DiffText" },
+    more = { link = "WarningMsg" },
     error = { link = "Error" },
     separator = { link = "FloatBorder" },
     header = { link = "Normal" },
@@ -23,6 +29,8 @@ local default_hl = {
 local hl_names = {
     input_hint = "RgFancyInputHint",
     input_hint_notice = "RgFancyInputHintNotice",
+    input_error = "RgFancyInputError",
+    input_error_msg = "RgFancyInputErrorMsg",
     path = "RgFancyPath",
     line_idx = "RgFancyLineNr",
     cursor_line_idx = "RgFancyCursorLineNr",
@@ -30,6 +38,10 @@ local hl_names = {
     count = "RgFancyCount",
     context = "RgFancyContext",
     matched = "RgFancyMatched",
+    matched_span = "RgFancyMatchedSpan",
+    replaced = "RgFancyReplaced",
+    replaced_span = "RgFancyReplacedSpan",
+    more = "RgFancyMore",
     error = "RgFancyError",
     separator = "RgFancySeparator",
     header = "RgFancyHeader",
@@ -67,6 +79,7 @@ for key, hl in pairs(hl_names) do
                 hl_group = hl,
             }
             if args.hl_eol then opts.hl_eol = true end
+            if args.priority then opts.priority = args.priority end
 
             return api.nvim_buf_set_extmark(buf, ns, args.start_line, args.start_col, opts)
         end
@@ -25,6 +25,467 @@ local setups = {
 
 local open_input_if_empty = true
 
+local search_options = {}
+
+-- Lines shown before/after each match; changed by more_context and less_context.
+local context = { before = 2, after = 2 }
+
+local function to_context_length(n, default)
+    if type(n) ~= "number" then return default end
+    return math.max(0, math.floor(n))
+end
+
+-- "regex" or "literal"; toggled by toggle_literal.
+local pattern_mode = "regex"
+
+-- Whether to list the lines not matching; toggled by toggle_invert.
+local invert = false
+
+-- The latest input for each tab, rerun when the context length or the mode changes.
+local last_inputs = {}
+
+local render_interval_ms = 100
+
+-- The latest stream for each tab; results of an older search are no longer rendered.
+local latest_streams = {}
+
+local function cancel_latest_stream(tab)
+    local latest = latest_streams[tab]
+    if latest and latest.id and not latest.done then
+        rpc.call.cancel({ latest.id })
+    end
+end
+
+-- Paths are sent only with the binary_paths search option.
+local function notify_skipped_binary(paths)
+    if not paths or paths == vim.NIL or not next(paths) then return end
+    vim.notify("[rg-fancy] Skipped binary files:\n" .. table.concat(paths, "\n"), vim.log.levels.INFO)
+end
+
+local function stream_handler(input)
+    local tab = vim.api.nvim_get_current_tabpage()
+    cancel_latest_stream(tab)
+
+    local token = { id = nil, done = false }
+    latest_streams[tab] = token
+
+    -- Batches arrive in the order the walker threads finish files; they are sorted by path
+    -- on render so that the output is stable between runs.
+    local batches = {}
+    local summary = {}
+    local rendered = false
+    local pending = false
+    local done = false
+
+    local render = function()
+        pending = false
+        if latest_streams[tab] ~= token then return end
+        if vim.api.nvim_get_current_tabpage() ~= tab then return end
+
+        table.sort(batches, function(a, b) return a.path < b.path end)
+
+        local results = {}
+        local errors = {}
+        for _, batch in ipairs(batches) do
+            vim.list_extend(results, batch.results)
+            vim.list_extend(errors, batch.errors)
+        end
+
+        ui.results.set(vim.list_extend(results, errors), input, rendered, summary)
+        rendered = true
+    end
+
+    return {
+        on_batch = function(batch)
+            local file = { path = "", results = {}, errors = {} }
+            for _, result in ipairs(batch) do
+                if result.path and result.path ~= vim.NIL then file.path = result.path end
+
+                if result.error and result.error ~= vim.NIL then
+                    table.insert(file.errors, result)
+                else
+                    table.insert(file.results, result)
+                end
+            end
+            table.insert(batches, file)
+
+            if pending or done then return end
+            pending = true
+            vim.defer_fn(function()
+                if not done then render() end
+            end, render_interval_ms)
+        end,
+
+        on_done = function(totals)
+            done = true
+            token.done = true
+            summary.truncated = totals.truncated
+            summary.cancelled = totals.cancelled
+            summary.binary = totals.binary
+            render()
+
+            if totals.glob_errors and next(totals.glob_errors) and latest_streams[tab] == token then
+                local globs = {}
+                for _, err in ipairs(totals.glob_errors) do
+                    ui.input.set_glob_error(err.index, err.error)
+                    table.insert(globs, err.glob)
+                end
+                vim.notify("[rg-fancy] Skipped invalid globs: " .. table.concat(globs, " "), vim.log.levels.WARN)
+            end
+
+            if latest_streams[tab] == token then
+                notify_skipped_binary(totals.binary_paths)
+            end
+
+            if totals.truncated and latest_streams[tab] == token then
+                vim.notify("[rg-fancy] Too many matches; showing partial results", vim.log.levels.WARN)
+            end
+
+            if totals.cancelled and latest_streams[tab] == token then
+                vim.notify("[rg-fancy] Search cancelled; showing partial results", vim.log.levels.INFO)
+            end
+        end,
+
+        set_id = function(id)
+            token.id = id
+        end,
+    }
+end
+
+local function current_options()
+    return vim.tbl_extend("force", {
+        before_context = context.before,
+        after_context = context.after,
+        mode = pattern_mode,
+        invert = invert,
+    }, search_options)
+end
+
+local function set_pattern_error(err, msg)
+    local span = nil
+    if err.span and err.span ~= vim.NIL then span = err.span end
+    ui.input.set_error("pattern", span, msg)
+end
+
+local function run_grep(input)
+    last_inputs[vim.api.nvim_get_current_tabpage()] = input
+
+    ui.input.clear_errors()
+
+    local options = current_options()
+    input.invert = options.invert
+
+    local handler = stream_handler(input)
+    local id, err = rpc.call.grep_stream(input.cwd, input.path, input.pattern, input.glob, options, handler)
+    if id then
+        handler.set_id(id)
+    elseif err and err.error then
+        -- Only pattern errors carry a kind
+        if err.kind then set_pattern_error(err) end
+        for _, glob_err in ipairs(err.glob_errors or {}) do
+            ui.input.set_glob_error(glob_err.index, glob_err.error)
+        end
+        vim.notify("[rg-fancy] " .. err.error, vim.log.levels.ERROR)
+    end
+end
+
+-- The latest replace_preview for each tab, applied by apply_replace.
+local last_previews = {}
+
+-- Searches with the replacement, and shows the rewritten lines under the matched ones.
+local function run_replace_preview(input, replacement, preserve_case)
+    cancel_latest_stream(vim.api.nvim_get_current_tabpage())
+    ui.input.clear_errors()
+
+    local options = current_options()
+    local call = rpc.call.replace_preview
+    if preserve_case then
+        options.preserve_case = true
+        call = rpc.call.replace_case_preview
+    end
+
+    local results = call(input.cwd, input.path, input.pattern, input.glob, replacement, options)
+    if type(results) ~= "table" then return end
+    if results.error then
+        if results.kind then set_pattern_error(results) end
+        vim.notify("[rg-fancy] " .. results.error, vim.log.levels.ERROR)
+        return
+    end
+
+    -- The last item is { truncated, cancelled, binary, binary_paths } if matches were cut at
+    -- max_matches, the search was cancelled, or binary files were skipped.
+    local summary = {}
+    local last = results[#results]
+    if last and last.truncated ~= nil then
+        summary = table.remove(results)
+    end
+
+    ui.results.set(results, input, false, summary)
+    notify_skipped_binary(summary.binary_paths)
+
+    local files = {}
+    local seen = {}
+    for _, result in ipairs(results) do
+        if result.hash and result.hash ~= vim.NIL and not seen[result.path] then
+            seen[result.path] = true
+            table.insert(files, { result.path, result.hash })
+        end
+    end
+    last_previews[vim.api.nvim_get_current_tabpage()] = {
+        input = input,
+        replacement = replacement,
+        files = files,
+        options = options,
+    }
+end
+
+local function apply_last_preview()
+    local tab = vim.api.nvim_get_current_tabpage()
+    local preview = last_previews[tab]
+    if not preview or not next(preview.files) then
+        vim.notify("[rg-fancy] Nothing to replace; run replace_preview first", vim.log.levels.WARN)
+        return
+    end
+
+    local choice = vim.fn.confirm(
+        string.format("Replace matches in %d files?", #preview.files),
+        "&Yes\n&No",
+        2
+    )
+    if choice ~= 1 then return end
+
+    local input = preview.input
+    local edits = rpc.call.apply_replace(input.cwd, input.pattern, preview.replacement, preview.files, preview.options)
+    if type(edits) ~= "table" then return end
+    if edits.error then
+        vim.notify("[rg-fancy] " .. edits.error, vim.log.levels.ERROR)
+        return
+    end
+    last_previews[tab] = nil
+
+    local edited, count = 0, 0
+    local skipped = {}
+    for _, edit in ipairs(edits) do
+        if edit.status == "edited" then
+            edited = edited + 1
+            count = count + edit.count
+        elseif edit.status == "conflict" then
+            table.insert(skipped, edit.path .. ": changed since the preview")
+        elseif edit.status == "failed" then
+            table.insert(skipped, edit.path .. ": " .. tostring(edit.error))
+        end
+    end
+
+    vim.cmd("checktime")
+
+    local msg = string.format("[rg-fancy] Replaced %d matches in %d files", count, edited)
+    if next(skipped) then
+        msg = msg .. "; skipped:\n" .. table.concat(skipped, "\n")
+        vim.notify(msg, vim.log.levels.WARN)
+    else
+        vim.notify(msg, vim.log.levels.INFO)
+    end
+end
+
+local function write_line_edits()
+    local edits = ui.results.get_line_edits()
+    if not edits then return end
+    if not next(edits) then
+        vim.notify("[rg-fancy] No edited lines", vim.log.levels.INFO)
+        return
+    end
+
+    local results = rpc.call.apply_line_edits(vim.uv.cwd(), edits)
+    if type(results) ~= "table" then return end
+    if results.error then
+        vim.notify("[rg-fancy] " .. results.error, vim.log.levels.ERROR)
+        return
+    end
+
+    local edited, count = 0, 0
+    local skipped = {}
+    for _, result in ipairs(results) do
+        if result.status == "edited" then
+            edited = edited + 1
+            count = count + result.count
+        elseif result.status == "conflict" then
+            for _, issue in ipairs(result.issues or {}) do
+                table.insert(skipped, string.format("%s:%d: %s", result.path, issue.line, issue.reason))
+            end
+        elseif result.status == "failed" then
+            table.insert(skipped, result.path .. ": " .. tostring(result.error))
+        end
+    end
+
+    vim.cmd("checktime")
+
+    local msg = string.format("[rg-fancy] Wrote %d lines in %d files", count, edited)
+    if next(skipped) then
+        msg = msg .. "; skipped files with:\n" .. table.concat(skipped, "\n")
+        vim.notify(msg, vim.log.levels.WARN)
+    else
+        vim.notify(msg, vim.log.levels.INFO)
+    end
+end
+
+-- Lines of unchanged text around each change of diff_replace
+local diff_context = 3
+
+-- Opens the replacements as a unified diff in a new tab, which can be saved as a patch.
+local function run_diff_replace(input, replacement)
+    ui.input.clear_errors()
+
+    local results = rpc.call.diff_replace(input.cwd, input.path, input.pattern, input.glob, replacement, { context = diff_context }, current_options())
+    if type(results) ~= "table" then return end
+    if results.error then
+        if results.kind then set_pattern_error(results) end
+        vim.notify("[rg-fancy] " .. results.error, vim.log.levels.ERROR)
+        return
+    end
+
+    local lines = {}
+    local errors = {}
+    for _, file in ipairs(results.files) do
+        if file.diff then
+            vim.list_extend(lines, vim.split(file.diff, "\n", { trimempty = true }))
+        else
+            table.insert(errors, file.path .. ": " .. tostring(file.error))
+        end
+    end
+    if next(errors) then
+        vim.notify("[rg-fancy] Skipped:\n" .. table.concat(errors, "\n"), vim.log.levels.WARN)
+    end
+    if not next(lines) then
+        vim.notify("[rg-fancy] Nothing to replace", vim.log.levels.INFO)
+        return
+    end
+
+    vim.cmd("tabnew")
+    local buf = vim.api.nvim_get_current_buf()
+    vim.api.nvim_buf_set_lines(buf, 0, -1, false, lines)
+    vim.api.nvim_set_option_value("buftype", "nofile", { buf = buf })
+    vim.api.nvim_set_option_value("bufhidden", "wipe", { buf = buf })
+    vim.api.nvim_set_option_value("filetype", "diff", { buf = buf })
+end
+
+-- Shows the renames planned from the input, and performs them after a confirmation.
+local function run_rename_paths(input, replacement)
+    local plan = rpc.call.rename_paths(input.cwd, input.path, input.pattern, input.glob, replacement, current_options())
+    if type(plan) ~= "table" then return end
+    if plan.error then
+        if plan.kind then set_pattern_error(plan) end
+        vim.notify("[rg-fancy] " .. plan.error, vim.log.levels.ERROR)
+        return
+    end
+
+    local lines = {}
+    local renames = {}
+    for _, rename in ipairs(plan.renames) do
+        local line = rename.from .. " -> " .. rename.to
+        if rename.status == "ready" or rename.status == "case_only" then
+            table.insert(renames, { rename.from, rename.to })
+        end
+        if rename.status ~= "ready" then
+            line = line .. " (" .. rename.status .. ")"
+        end
+        table.insert(lines, line)
+    end
+    if not next(lines) then
+        vim.notify("[rg-fancy] No paths to rename", vim.log.levels.INFO)
+        return
+    end
+
+    local choice = vim.fn.confirm(
+        table.concat(lines, "\n") .. string.format("\n\nRename %d of %d paths?", #renames, #lines),
+        "&Yes\n&No",
+        2
+    )
+    if choice ~= 1 or not next(renames) then return end
+
+    local results = rpc.call.apply_renames(input.cwd, input.path, renames)
+    if type(results) ~= "table" then return end
+    if results.error then
+        vim.notify("[rg-fancy] " .. results.error, vim.log.levels.ERROR)
+        return
+    end
+
+    local renamed = 0
+    local skipped = {}
+    for _, result in ipairs(results) do
+        if result.status == "renamed" then
+            renamed = renamed + 1
+        else
+            table.insert(skipped, result.from .. ": " .. tostring(result.error or result.status))
+        end
+    end
+
+    vim.cmd("checktime")
+
+    local msg = string.format("[rg-fancy] Renamed %d paths", renamed)
+    if next(skipped) then
+        msg = msg .. "; skipped:\n" .. table.concat(skipped, "\n")
+        vim.notify(msg, vim.log.levels.WARN)
+    else
+        vim.notify(msg, vim.log.levels.INFO)
+    end
+end
+
+local validate_on_change = true
+local validate_delay_ms = 200
+local validate_timer = nil
+
+-- Marks invalid fields of the input window while typing.
+local function validate_input()
+    if validate_timer and not validate_timer:is_closing() then
+        validate_timer:stop()
+        validate_timer:close()
+    end
+
+    validate_timer = vim.defer_fn(function()
+        validate_timer = nil
+
+        local input = ui.input.get()
+        if not input then return end
+
+        local diagnostics = rpc.call.validate(vim.uv.cwd(), input.path, input.pattern, input.glob, current_options())
+        ui.input.clear_errors()
+        if type(diagnostics) ~= "table" then return end
+
+        if diagnostics.pattern then
+            set_pattern_error(diagnostics.pattern, diagnostics.pattern.error)
+        end
+        for _, err in ipairs(diagnostics.glob or {}) do
+            ui.input.set_glob_error(err.index, err.error)
+        end
+        if diagnostics.path then
+            ui.input.set_error("path", nil, diagnostics.path.error)
+        end
+    end, validate_delay_ms)
+end
+
+-- Lines of a member of an archive for open_item_current, or nil after notifying the error.
+local function read_member(path)
+    local result = rpc.call.read_member(vim.uv.cwd(), path)
+    if type(result) ~= "table" then return end
+
+    if result.error then
+        vim.notify("[rg-fancy] " .. result.error, vim.log.levels.ERROR)
+        return
+    end
+    return result.lines
+end
+
+local function rerun_last_input()
+    local input = last_inputs[vim.api.nvim_get_current_tabpage()]
+    if input then run_grep(input) end
+end
+
+local function change_context(delta)
+    context.before = math.max(0, context.before + delta)
+    context.after = math.max(0, context.after + delta)
+    rerun_last_input()
+end
+
 M.fn = {
     open_results = function()
         ui.results.open(setups.results)
@@ -55,15 +516,99 @@ M.fn = {
     grep = function()
         local input = ui.input.get()
         if not input then return end
-        local cwd = vim.uv.cwd()
-        local results = rpc.call.grep(cwd, input.path, input.pattern, input.glob)
-        if not results or results == vim.NIL then return end
+        input.cwd = vim.uv.cwd()
+
+        run_grep(input)
+    end,
+
+    replace_preview = function()
+        local input = ui.input.get()
+        if not input then return end
+        input.cwd = vim.uv.cwd()
+
+        vim.ui.input({ prompt = "Replace with: " }, function(replacement)
+            if not replacement then return end
+            run_replace_preview(input, replacement)
+        end)
+    end,
+
+    replace_case_preview = function()
+        local input = ui.input.get()
+        if not input then return end
+        input.cwd = vim.uv.cwd()
+
+        vim.ui.input({ prompt = "Replace preserving case with: " }, function(replacement)
+            if not replacement then return end
+            run_replace_preview(input, replacement, true)
+        end)
+    end,
+
+    apply_replace = apply_last_preview,
+
+    diff_replace = function()
+        local input = ui.input.get()
+        if not input then return end
+        input.cwd = vim.uv.cwd()
+
+        vim.ui.input({ prompt = "Replace with: " }, function(replacement)
+            if not replacement then return end
+            run_diff_replace(input, replacement)
+        end)
+    end,
+
+    rename_paths = function()
+        local input = ui.input.get()
+        if not input then return end
+        input.cwd = vim.uv.cwd()
+
+        vim.ui.input({ prompt = "Rename paths to: " }, function(replacement)
+            if not replacement then return end
+            run_rename_paths(input, replacement)
+        end)
+    end,
+
+    edit_results = ui.results.start_edit,
 
-        input.cwd = cwd
-        ui.results.set(results, input)
+    write_edits = function()
+        write_line_edits()
+        rerun_last_input()
     end,
 
-    open_item_current = ui.results.open_item_current,
+    more_context = function()
+        change_context(1)
+    end,
+
+    less_context = function()
+        change_context(-1)
+    end,
+
+    toggle_literal = function()
+        if pattern_mode == "literal" then
+            pattern_mode = "regex"
+        else
+            pattern_mode = "literal"
+        end
+        vim.notify("[rg-fancy] Pattern mode: " .. pattern_mode, vim.log.levels.INFO)
+        rerun_last_input()
+    end,
+
+    toggle_invert = function()
+        invert = not invert
+        if invert then
+            vim.notify("[rg-fancy] Listing lines not matching", vim.log.levels.INFO)
+        else
+            vim.notify("[rg-fancy] Listing matching lines", vim.log.levels.INFO)
+        end
+        rerun_last_input()
+    end,
+
+    cancel = function()
+        cancel_latest_stream(vim.api.nvim_get_current_tabpage())
+    end,
+
+    open_item_current = function()
+        ui.results.open_item_current(read_member)
+    end,
     goto_prev_item_line = ui.results.goto_prev_item_line,
     goto_next_item_line = ui.results.goto_next_item_line,
     goto_first_item_line = ui.results.goto_first_item_line,
@@ -106,6 +651,38 @@ function M.setup(opts)
         open_input_if_empty = opts.open_input_if_empty
     end
 
+    if opts.validate_on_change ~= nil then
+        validate_on_change = opts.validate_on_change
+    end
+    if validate_on_change then
+        setups.input.on_change = validate_input
+    end
+
+    if opts.diff_context ~= nil then
+        diff_context = to_context_length(opts.diff_context, diff_context)
+    end
+
+    if opts.context_length ~= nil then
+        context.before = to_context_length(opts.context_length, context.before)
+        context.after = to_context_length(opts.context_length, context.after)
+    end
+
+    if opts.search then
+        search_options = vim.deepcopy(opts.search)
+        -- Context lengths, the mode and invert are kept apart, so that more_context,
+        -- less_context, toggle_literal and toggle_invert apply.
+        context.before = to_context_length(search_options.before_context, context.before)
+        context.after = to_context_length(search_options.after_context, context.after)
+        search_options.before_context = nil
+        search_options.after_context = nil
+
+        if search_options.mode then pattern_mode = search_options.mode end
+        search_options.mode = nil
+
+        if search_options.invert ~= nil then invert = search_options.invert end
+        search_options.invert = nil
+    end
+
     if opts.keymaps then
         if opts.keymaps.global then
             for _, args in ipairs(opts.keymaps.global) do
@@ -121,7 +698,10 @@ function M.setup(opts)
 
     hl.set_highlight_groups(opts.hl)
 
-    rpc.register(opts.plugin_dir, opts.rpc_ns, opts.context_length)
+    rpc.register(opts.plugin_dir, opts.rpc_ns, {
+        max_searches = opts.max_searches,
+        threads = opts.threads,
+    })
 end
 
 return M
@@ -11,10 +11,16 @@ local states = {
 local api = vim.api
 
 local virt_ns = api.nvim_create_namespace("NaughieRgFancyVirt")
+local input_error_ns = api.nvim_create_namespace("NaughieRgFancyInputError")
+-- Marks the rows of matched and context lines, which can be edited and written back.
+local edit_ns = api.nvim_create_namespace("NaughieRgFancyEdit")
 
 local input_height = 3
+-- Rows of the input fields
+local input_rows = { path = 0, pattern = 1, glob = 2 }
 function M.input(buf)
     api.nvim_buf_clear_namespace(buf, virt_ns, 0, -1)
+    api.nvim_buf_clear_namespace(buf, input_error_ns, 0, -1)
 
     api.nvim_buf_set_lines(buf, 0, -1, false, { ".", "", "" })
 
@@ -42,8 +48,10 @@ local function create_result_renderer(buf)
     local lines = {}
     local exts = {}
 
-    local inner_states = { path = nil, base_line = nil, offset = nil }
+    local inner_states = { path = nil, base_line = nil, base_col = nil, offset = nil, archive = nil }
     local line_idx_exts = {}
+    local editable = {}
+    local edit_marks = {}
 
     local max_line_idx_width = 0
 
@@ -73,6 +81,17 @@ local function create_result_renderer(buf)
         end
     end
 
+    local insert_hl = function(start_col, end_col, hl_group, priority)
+        table.insert(exts, {
+            start_line = #lines - 1,
+            end_line = #lines - 1,
+            start_col = start_col,
+            end_col = end_col,
+            hl_group = hl_group,
+            priority = priority,
+        })
+    end
+
     local insert_virt_text = function(virt_text, hl_group, opts)
         local ext_args = opts
         ext_args.virt_text = virt_text
@@ -89,10 +108,31 @@ local function create_result_renderer(buf)
             inner_states.base_line = line_idx
         end,
 
+        set_base_col = function(col)
+            inner_states.base_col = col
+        end,
+
         set_offset = function(offset)
             inner_states.offset = offset
         end,
 
+        -- The path is a member of an archive, opened read-only.
+        set_archive = function()
+            inner_states.archive = true
+        end,
+
+        set_hl = insert_hl,
+
+        -- line_idx: 1-based line number of the last inserted line in the file
+        set_editable = function(line_idx, original)
+            table.insert(editable, {
+                row = #lines - 1,
+                path = inner_states.path,
+                line = line_idx,
+                original = original,
+            })
+        end,
+
         set_virt_text = function(virt_text, hl_group, opts)
             insert_virt_text(virt_text, hl_group, opts or { pos = "inline", col = 0 })
         end,
@@ -173,6 +213,11 @@ local function create_result_renderer(buf)
         update_line_idx_states = function()
             local current_states = states.results.get()
             current_states.items[states_idx].line_idx_exts = line_idx_exts
+
+            current_states.edits = current_states.edits or {}
+            for id, item in pairs(edit_marks) do
+                current_states.edits[id] = item
+            end
         end,
 
         append_after = function(total_lines)
@@ -207,9 +252,16 @@ local function create_result_renderer(buf)
                         end_line = ext.end_line + total_lines,
                         start_col = ext.start_col,
                         end_col = ext.end_col,
+                        priority = ext.priority,
                     })
                 end
             end
+
+            for _, item in ipairs(editable) do
+                local ext_id = api.nvim_buf_set_extmark(buf, edit_ns, item.row + total_lines, 0, {})
+                edit_marks[ext_id] = { path = item.path, line = item.line, original = item.original }
+            end
+
             return total_lines + #lines
         end,
     }
@@ -226,6 +278,36 @@ end
 
 local function render_matched(result, renderer, input, count)
     renderer.set_path(result.path, input.cwd, count)
+    -- Lines are written back as they are, so only those of plain UTF-8 files can be edited.
+    local editable = true
+    -- Offset of the first NUL, with the binary search option other than "skip"
+    if result.binary and result.binary ~= vim.NIL then
+        renderer.set_virt_text("  (binary)", "more", { pos = "eol", col = 0 })
+        editable = false
+    end
+    -- The encoding decoded from, e.g. "UTF-16LE" or "Shift_JIS"
+    if result.encoding and result.encoding ~= vim.NIL and result.encoding ~= "UTF-8" then
+        renderer.set_virt_text("  (" .. result.encoding .. ")", "more", { pos = "eol", col = 0 })
+        editable = false
+    end
+    -- "gzip", "xz", "zstd" or "bzip2", with the decompress search option
+    if result.decompressed and result.decompressed ~= vim.NIL then
+        renderer.set_virt_text("  (" .. result.decompressed .. ")", "more", { pos = "eol", col = 0 })
+        editable = false
+    end
+    -- A member of an archive, with the archives search option
+    if result.archive and result.archive ~= vim.NIL then
+        renderer.set_archive()
+        editable = false
+    end
+    -- Searched in a modified buffer, whose lines may differ from the file
+    if result.unsaved then
+        renderer.set_virt_text("  (unsaved)", "more", { pos = "eol", col = 0 })
+        editable = false
+    end
+    local set_editable = function(line_idx, original)
+        if editable then renderer.set_editable(line_idx, original) end
+    end
 
     local base_line = nil
     if result.line_idx and result.line_idx ~= vim.NIL then
@@ -242,33 +324,67 @@ local function render_matched(result, renderer, input, count)
         if item and item ~= vim.NIL then
             renderer.insert_line(item, "context")
             renderer.set_line_idx(line_idx)
+            set_editable(base_line - i, item)
             count_before = count_before + 1
         end
     end
 
     if result.matched and result.matched ~= vim.NIL then
         renderer.set_offset(count_before + 3)
-        for _, matched_line in ipairs(result.matched) do
+
+        local spans = {}
+        if result.spans and result.spans ~= vim.NIL then spans = result.spans end
+        if spans[1] then renderer.set_base_col(spans[1].start) end
+
+        for i, matched_line in ipairs(result.matched) do
             local line_idx = tostring(base_line)
 
             renderer.insert_line(matched_line, "matched")
+            for _, span in ipairs(spans) do
+                if span.line == i - 1 then
+                    renderer.set_hl(span.start, span["end"], "matched_span", 4200)
+                end
+            end
             renderer.set_tick_around(0, string.len(matched_line), "matched_tick")
             renderer.set_line_idx(line_idx, true)
+            set_editable(base_line, matched_line)
 
             base_line = base_line + 1
         end
     end
 
+    if result.replaced and result.replaced ~= vim.NIL then
+        for i, replaced_line in ipairs(result.replaced.lines) do
+            renderer.insert_line(replaced_line, "replaced")
+            for _, span in ipairs(result.replaced.spans) do
+                if span.line == i - 1 then
+                    renderer.set_hl(span.start, span["end"], "replaced_span", 4200)
+                end
+            end
+            renderer.set_line_idx("+")
+        end
+    end
+
     for i, item in ipairs(result.after) do
         local line_idx = tostring(base_line + i - 1)
         if item and item ~= vim.NIL then
             renderer.insert_line(item, "context")
             renderer.set_line_idx(line_idx)
+            set_editable(base_line + i - 1, item)
         end
     end
+
+    -- Set on the last result of a file over max_matches_per_file
+    if result.more and result.more ~= vim.NIL then
+        renderer.insert_line("")
+        renderer.insert_line(string.format(" \u{f141} %d more matches in this file", result.more), "more")
+    end
 end
 
-local function render_header(buf, results, input)
+-- summary = { truncated, cancelled, binary }, where truncated tells that matches were cut at
+-- max_matches, cancelled that the search was cancelled, and binary is the number of files skipped
+-- as binary.
+local function render_header(buf, results, input, summary)
     local count = 0
     for _, result in ipairs(results) do
         if not result.error or result.error == vim.NIL then
@@ -278,9 +394,15 @@ local function render_header(buf, results, input)
 
     local matches_str = tostring(count)
     local matches_len = vim.fn.strwidth(matches_str)
+    local partial = ""
+    if summary and (summary.truncated or summary.cancelled) then partial = " (partial)" end
     local errors_str = tostring(#results - count)
     local errors_len = vim.fn.strwidth(errors_str)
-    local max_stat_len =math.max(matches_len, errors_len)
+    local binary = summary and summary.binary
+    if binary == vim.NIL then binary = nil end
+    local binary_str = tostring(binary or 0)
+    local binary_len = vim.fn.strwidth(binary_str)
+    local max_stat_len =math.max(matches_len, errors_len, binary_len)
 
     if matches_len ~= max_stat_len then
         matches_str = string.rep(" ", max_stat_len - matches_len) .. matches_str
@@ -288,27 +410,41 @@ local function render_header(buf, results, input)
     if errors_len ~= max_stat_len then
         errors_str = string.rep(" ", max_stat_len - errors_len) .. errors_str
     end
+    if binary_len ~= max_stat_len then
+        binary_str = string.rep(" ", max_stat_len - binary_len) .. binary_str
+    end
 
     local glob = table.concat(input.glob, ' ')
     if glob == "" then glob = "(default)" end
 
+    local pattern = input.pattern
+    if input.invert then pattern = pattern .. " (inverted)" end
+
+    local suffix_len = vim.fn.strwidth(partial)
+    if binary and binary > 0 then suffix_len = math.max(suffix_len, vim.fn.strwidth(" skipped")) end
+
     local max_width = 13 + math.max(
-        max_stat_len,
+        max_stat_len + suffix_len,
         vim.fn.strwidth(input.path),
-        vim.fn.strwidth(input.pattern),
+        vim.fn.strwidth(pattern),
         vim.fn.strwidth(glob)
     )
     local rule = string.rep("─", max_width + 2)
 
     local header = {
         "\u{e370} Grep summary\u{e370}",
-        "    \u{f422} #matches \u{f061} " .. matches_str,
+        "    \u{f422} #matches \u{f061} " .. matches_str .. partial,
         "    \u{f421} #errors  \u{f061} " .. errors_str,
+    }
+    if binary and binary > 0 then
+        table.insert(header, "    \u{f471} #binary  \u{f061} " .. binary_str .. " skipped")
+    end
+    vim.list_extend(header, {
         "   " .. rule,
         "    \u{f034e} Path     \u{f061} " .. input.path,
-        "    \u{f0451} Pattern  \u{f061} " .. input.pattern,
+        "    \u{f0451} Pattern  \u{f061} " .. pattern,
         "    \u{eb01} Glob     \u{f061} " .. glob,
-    }
+    })
     api.nvim_buf_set_lines(buf, 0, -1, false,  header)
     hl.set_extmark.header(buf, {
         start_line = 0,
@@ -322,14 +458,15 @@ local function render_header(buf, results, input)
 end
 M.header = render_header
 
-function M.results(buf, win, results, input)
+function M.results(buf, win, results, input, summary)
     local win_width = api.nvim_win_get_width(win)
 
     api.nvim_set_option_value("modifiable", true, { buf = buf })
 
     hl.clear_extmarks(buf)
+    api.nvim_buf_clear_namespace(buf, edit_ns, 0, -1)
     states.results.clear()
-    local total_lines, num_matches = render_header(buf, results, input)
+    local total_lines, num_matches = render_header(buf, results, input, summary)
     local count = 0
     for _, result in ipairs(results) do
         local renderer = create_result_renderer(buf)
@@ -378,6 +515,54 @@ M.manipulate = {
             api.nvim_win_set_cursor(win, { next_row, #next_line + 1 })
         end,
 
+        -- span = { start, end } in bytes; the whole field is underlined if omitted.
+        -- msg is shown at the end of the line, if any.
+        set_error = function(buf, field, span, msg)
+            local row = input_rows[field]
+            if not row then return end
+            local line = api.nvim_buf_get_lines(buf, row, row + 1, false)[1]
+            if not line then return end
+
+            local start_col, end_col = 0, #line
+            if span then
+                start_col = math.min(span.start, #line)
+                end_col = math.min(math.max(span["end"], start_col + 1), #line)
+            end
+            if start_col >= end_col then
+                -- Nothing to underline, e.g. an unclosed group at the end of the pattern
+                start_col = math.max(end_col - 1, 0)
+            end
+
+            api.nvim_buf_set_extmark(buf, input_error_ns, row, start_col, {
+                end_col = end_col,
+                hl_group = hl.hl_groups.input_error,
+            })
+            if msg then
+                api.nvim_buf_set_extmark(buf, input_error_ns, row, 0, {
+                    virt_text = { { "  " .. msg, hl.hl_groups.input_error_msg } },
+                    virt_text_pos = "eol",
+                })
+            end
+        end,
+
+        -- Byte range of the index-th (0-based) glob in the glob field
+        glob_span = function(buf, index)
+            local line = api.nvim_buf_get_lines(buf, input_rows.glob, input_rows.glob + 1, false)[1]
+            if not line then return end
+
+            local i = 0
+            for start, item in string.gmatch(line, "()(%S+)") do
+                if i == index then
+                    return { start = start - 1, ["end"] = start - 1 + #item }
+                end
+                i = i + 1
+            end
+        end,
+
+        clear_errors = function(buf)
+            api.nvim_buf_clear_namespace(buf, input_error_ns, 0, -1)
+        end,
+
         get = function(buf)
             local lines = api.nvim_buf_get_lines(buf, 0, input_height, false)
             if #lines ~= input_height then return end
@@ -406,6 +591,24 @@ M.manipulate = {
             end
         end,
 
+        get_item_line = function(row)
+            local current_states = states.results.get()
+            if not current_states then return end
+
+            for i = row - 1, 0, -1 do
+                local on_row = current_states.items[i]
+                if on_row then
+                    if on_row.offset then
+                        return i + 1 + on_row.offset, on_row.line_idx_exts
+                    else
+                        return i + 2, on_row.line_idx_exts
+                    end
+                end
+            end
+
+            return M.manipulate.results.get_next_item_line(row)
+        end,
+
         get_prev_item_line = function(row)
             local current_states = states.results.get()
             if not current_states then return end
@@ -447,6 +650,35 @@ M.manipulate = {
             return M.manipulate.results.get_next_item_line(1)
         end,
 
+        -- Returns { { path, line, original, new }, ... } for the edited lines of the results.
+        get_line_edits = function(buf)
+            local current_states = states.results.get()
+            if not current_states or not current_states.edits then return {} end
+
+            local ids_on_row = {}
+            for _, mark in ipairs(api.nvim_buf_get_extmarks(buf, edit_ns, 0, -1, {})) do
+                local id, row = mark[1], mark[2]
+                ids_on_row[row] = ids_on_row[row] or {}
+                table.insert(ids_on_row[row], id)
+            end
+
+            local edits = {}
+            for row, ids in pairs(ids_on_row) do
+                -- Marks of deleted lines move onto the next row, whose line is then left as is.
+                local item = #ids == 1 and current_states.edits[ids[1]]
+                local text = api.nvim_buf_get_lines(buf, row, row + 1, false)[1]
+                if item and text and text ~= item.original then
+                    table.insert(edits, { item.path, item.line, item.original, text })
+                end
+            end
+
+            table.sort(edits, function(a, b)
+                if a[1] ~= b[1] then return a[1] < b[1] end
+                return a[2] < b[2]
+            end)
+            return edits
+        end,
+
         get_last_item_line = function(row)
             local current_states = states.results.get()
             if not current_states then return end
@@ -1,23 +1,45 @@
 local M = {}
 
 local default_ns = "rg-fancy"
-local default_context = 2
 
 local router = require("nvim-router")
 
 local rpc = { request = function() end }
 
-local function to_context_length(context_length)
-    if type(context_length) ~= "number" then return default_context end
-    local n = math.floor(context_length)
-    if n < 1 or n > 10 then return default_context end
-    return n
+-- Sent as `configure` before the first request, since the Rust process is spawned lazily.
+local pending_config = nil
+
+local function request(method, ...)
+    if pending_config then
+        local config = pending_config
+        pending_config = nil
+        rpc.request("configure", config)
+    end
+    return rpc.request(method, ...)
+end
+
+-- Searches answered within the request block Neovim until they finish. When the wait is
+-- interrupted, e.g. by <C-c>, every search in flight is cancelled so that the walk stops as well.
+local function search_request(method, ...)
+    local ok, result = pcall(request, method, ...)
+    if ok then return result end
+
+    request("cancel")
+    return { error = "Search interrupted: " .. tostring(result) }
 end
 
-function M.register(plugin_dir, new_ns, context_length)
+local function to_option_pairs(opts)
+    local pairs_list = {}
+    for key, value in pairs(opts) do
+        table.insert(pairs_list, { key, value })
+    end
+    return pairs_list
+end
+
+function M.register(plugin_dir, new_ns, config)
     local info = {
         path = plugin_dir .. "/rg-fancy.rs",
-        handler = "NeovimHandler" .. tostring(to_context_length(context_length)),
+        handler = "NeovimHandler",
     }
 
     if new_ns then
@@ -28,11 +50,107 @@ function M.register(plugin_dir, new_ns, context_length)
 
     local new_rpc = router.register(info)
     rpc.request = new_rpc.request
+
+    if config and next(config) then
+        pending_config = to_option_pairs(config)
+    end
 end
 
+local streams = {}
+
+M.stream = {
+    dispatch = function(event, id, payload)
+        local handler = streams[id]
+        if not handler then return end
+
+        if event == "batch" then
+            handler.on_batch(payload)
+        elseif event == "done" then
+            streams[id] = nil
+            handler.on_done(payload)
+        end
+    end,
+}
+
 M.call = {
-    grep = function(cwd, path, pattern, glob)
-        return rpc.request("grep", cwd, path, pattern, glob)
+    grep = function(cwd, path, pattern, glob, options)
+        return search_request("grep", cwd, path, pattern, glob, to_option_pairs(options or {}))
+    end,
+
+    -- Results of grep, where each matched result has replaced = { lines, spans }.
+    replace_preview = function(cwd, path, pattern, glob, replacement, options)
+        return search_request("replace_preview", cwd, path, pattern, glob, replacement, to_option_pairs(options or {}))
+    end,
+
+    -- Same as replace_preview, but matches the words of the pattern in any casing style (user_id,
+    -- UserId, USER_ID, userId, user-id), and writes the replacement in the style of each match.
+    replace_case_preview = function(cwd, path, pattern, glob, replacement, options)
+        return search_request("replace_case_preview", cwd, path, pattern, glob, replacement, to_option_pairs(options or {}))
+    end,
+
+    -- files = { { path, hash }, ... } from the results of replace_preview
+    -- Returns { { path, status, count, error }, ... }, where status is one of edited, unchanged,
+    -- conflict or failed.
+    apply_replace = function(cwd, pattern, replacement, files, options)
+        return request("apply_replace", cwd, pattern, replacement, files, to_option_pairs(options or {}))
+    end,
+
+    -- edits = { { path, line, original, new }, ... }, where line is 1-based and original is the
+    -- line shown in the results.
+    -- Returns { { path, status, count, error, issues }, ... }; a file is left as is when any of its
+    -- lines has an issue = { line, reason }, where reason is one of stale, missing or conflict.
+    apply_line_edits = function(cwd, edits)
+        return request("apply_line_edits", cwd, edits)
+    end,
+
+    -- diff_options = { context = 3 }
+    -- Returns { files = { { path, diff } or { path, error }, ... }, glob_errors }, where diff is
+    -- a unified diff with the path relative to cwd.
+    diff_replace = function(cwd, path, pattern, glob, replacement, diff_options, options)
+        return search_request("diff_replace", cwd, path, pattern, glob, replacement, to_option_pairs(diff_options or {}), to_option_pairs(options or {}))
+    end,
+
+    -- The pattern matches the paths of files relative to path.
+    -- Returns { renames = { { from, to, status }, ... }, glob_errors }, where status is one of
+    -- ready, case_only, collision, exists or invalid.
+    rename_paths = function(cwd, path, pattern, glob, replacement, options)
+        return request("rename_paths", cwd, path, pattern, glob, replacement, to_option_pairs(options or {}))
+    end,
+
+    -- renames = { { from, to }, ... } relative to path
+    -- Returns { { from, to, status, error }, ... }, where status is renamed or failed if the rename
+    -- is still ready, and otherwise the status of the plan.
+    apply_renames = function(cwd, path, renames)
+        return request("apply_renames", cwd, path, renames)
+    end,
+
+    -- handler = { on_batch = function(results), on_done = function(totals) }
+    -- Returns the search id, or nil and an error object.
+    grep_stream = function(cwd, path, pattern, glob, options, handler)
+        local id = request("grep_stream", cwd, path, pattern, glob, to_option_pairs(options or {}))
+        if not id or id == vim.NIL then return end
+        if type(id) == "table" then return nil, id end
+
+        streams[id] = handler
+        return id
+    end,
+
+    -- path is a member of an archive, e.g. vendor.zip!/src/lib.rs, from the results of the archives
+    -- search option.
+    -- Returns { lines }, or { error, path }.
+    read_member = function(cwd, path)
+        return request("read_member", cwd, path)
+    end,
+
+    -- Returns { pattern = err, glob = { err, ... }, path = err }; valid fields are omitted.
+    validate = function(cwd, path, pattern, glob, options)
+        return request("validate", cwd, path, pattern, glob, to_option_pairs(options or {}))
+    end,
+
+    -- ids = { id, ... }; every search in flight is cancelled if omitted.
+    -- Returns the ids cancelled.
+    cancel = function(ids)
+        return request("cancel", ids)
     end,
 }
 
@@ -41,6 +41,23 @@ local goto_item_line = function(fn_get_item, current_row)
     end
 end
 
+-- Fills the current buffer, named like rg-fancy://vendor.zip!/src/lib.rs, with a member of an
+-- archive and makes it read-only.
+local set_member_lines = function(path, lines)
+    local buf = api.nvim_get_current_buf()
+    api.nvim_set_option_value("buftype", "nofile", { buf = buf })
+    api.nvim_set_option_value("swapfile", false, { buf = buf })
+    api.nvim_set_option_value("modifiable", true, { buf = buf })
+    api.nvim_buf_set_lines(buf, 0, -1, false, lines)
+    api.nvim_set_option_value("modifiable", false, { buf = buf })
+    api.nvim_set_option_value("readonly", true, { buf = buf })
+
+    local filetype = vim.filetype.match({ filename = path, buf = buf })
+    if filetype then
+        api.nvim_set_option_value("filetype", filetype, { buf = buf })
+    end
+end
+
 M.results = {
     open = function(setup)
         if ui.main.get_win() then
@@ -69,31 +86,48 @@ M.results = {
         return ui.main.focus()
     end,
 
-    set = function(new_results, input)
+    set = function(new_results, input, keep_cursor, summary)
         local buf = ui.main.get_buf()
         if not buf then return end
         local win = ui.main.get_win()
         if not win then return end
-        render.results(buf, win, new_results, input)
 
-        goto_item_line("get_next_item_line", 1)
+        local row = api.nvim_win_get_cursor(win)[1]
+        render.results(buf, win, new_results, input, summary)
+
+        if keep_cursor then
+            row = math.min(row, api.nvim_buf_line_count(buf))
+            goto_item_line("get_item_line", row)
+        else
+            goto_item_line("get_next_item_line", 1)
+        end
     end,
 
-    open_item_current = function()
+    -- read_member(path) returns the lines of a member of an archive, or nil.
+    open_item_current = function(read_member)
         local row = api.nvim_win_get_cursor(0)[1]
         local item = render.manipulate.results.get_item_current(row)
         if not item or not item.path then return end
 
         local path = vim.fn.fnameescape(item.path)
 
+        local member_lines = nil
+        if item.archive then
+            if not read_member then return end
+            member_lines = read_member(item.path)
+            if not member_lines then return end
+            path = vim.fn.fnameescape("rg-fancy://" .. item.path)
+        end
+
         local ok = myui.open_file_into_last_active_win(path)
         if not ok then
             myui.open_file_into_current_win(path)
         end
+        if member_lines then set_member_lines(item.path, member_lines) end
         myui.close_all()
 
         if item.base_line then
-            api.nvim_win_set_cursor(0, { item.base_line, 0 })
+            api.nvim_win_set_cursor(0, { item.base_line, item.base_col or 0 })
         end
     end,
     goto_prev_item_line = function()
@@ -114,6 +148,20 @@ M.results = {
     end,
 
     is_empty = render.manipulate.states.is_empty,
+
+    -- Lets matched and context lines be edited until the next search.
+    start_edit = function()
+        local buf = ui.main.get_buf()
+        if not buf then return end
+        api.nvim_set_option_value("modifiable", true, { buf = buf })
+        return true
+    end,
+
+    get_line_edits = function()
+        local buf = ui.main.get_buf()
+        if not buf then return end
+        return render.manipulate.results.get_line_edits(buf)
+    end,
 }
 
 M.input = {
@@ -131,6 +179,16 @@ M.input = {
                 end, { buffer = buf, silent = true })
 
                 render.input(buf)
+
+                if setup.on_change then
+                    api.nvim_create_autocmd({ "TextChanged", "TextChangedI" }, {
+                        group = augroup,
+                        buffer = buf,
+                        callback = function()
+                            setup.on_change(buf)
+                        end,
+                    })
+                end
             end)
             ui.companion.open_float(function(win)
                 local tab = api.nvim_get_current_tabpage()
@@ -169,6 +227,28 @@ M.input = {
         if not buf then return end
         return render.manipulate.input.get(buf)
     end,
+
+    -- field: "path", "pattern" or "glob"
+    set_error = function(field, span, msg)
+        local buf = ui.companion.get_buf()
+        if not buf then return end
+        render.manipulate.input.set_error(buf, field, span, msg)
+    end,
+
+    -- index: 0-based position of the glob in the glob field
+    set_glob_error = function(index, msg)
+        local buf = ui.companion.get_buf()
+        if not buf then return end
+        local span = render.manipulate.input.glob_span(buf, index)
+        if not span then return end
+        render.manipulate.input.set_error(buf, "glob", span, msg)
+    end,
+
+    clear_errors = function()
+        local buf = ui.companion.get_buf()
+        if not buf then return end
+        render.manipulate.input.clear_errors(buf)
+    end,
 }
 
 M.update_ui_opts = function(opts)
@@ -0,0 +1,232 @@
+// Members of zip and tar archives, searched as virtual files named like vendor.zip!/src/lib.rs.
+
+use crate::decompress::read_limited;
+
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+// Between the path of an archive and the name of a member
+pub const SEPARATOR: &str = "!/";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+impl Kind {
+    // Detected by the extension
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?;
+        if name.ends_with(".zip") {
+            Some(Self::Zip)
+        } else if name.ends_with(".tar") {
+            Some(Self::Tar)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(Self::TarGz)
+        } else {
+            None
+        }
+    }
+}
+
+// The archive and the member name of a path like vendor.zip!/src/lib.rs
+pub fn split_path(path: &str) -> Option<(&Path, Kind, &str)> {
+    path.match_indices(SEPARATOR).find_map(|(i, _)| {
+        let archive = Path::new(&path[..i]);
+        let kind = Kind::from_path(archive)?;
+        Some((archive, kind, &path[i + SEPARATOR.len()..]))
+    })
+}
+
+// The virtual path of a member, e.g. vendor.zip!/src/lib.rs
+pub fn member_path(archive: &Path, name: &str) -> PathBuf {
+    let mut path = archive.as_os_str().to_owned();
+    path.push(SEPARATOR);
+    path.push(name);
+    PathBuf::from(path)
+}
+
+fn for_each_tar_member(
+    reader: impl Read,
+    mut f: impl FnMut(String, io::Result<Vec<u8>>) -> bool,
+) -> io::Result<()> {
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let name = entry.path()?.to_string_lossy().into_owned();
+        if !f(name, read_limited(entry)) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// Calls `f` with the name and contents of each regular file in the archive, until it returns false.
+// Errors reading a member are passed to `f`, while those of the archive itself are returned.
+pub fn for_each_member(
+    kind: Kind,
+    path: &Path,
+    mut f: impl FnMut(String, io::Result<Vec<u8>>) -> bool,
+) -> io::Result<()> {
+    let file = BufReader::new(File::open(path)?);
+
+    match kind {
+        Kind::Zip => {
+            let mut archive = zip::ZipArchive::new(file)?;
+            for i in 0..archive.len() {
+                let member = archive.by_index(i)?;
+                if !member.is_file() {
+                    continue;
+                }
+                let name = member.name().to_string();
+                if !f(name, read_limited(member)) {
+                    break;
+                }
+            }
+            Ok(())
+        }
+        Kind::Tar => for_each_tar_member(file, f),
+        Kind::TarGz => for_each_tar_member(flate2::read::MultiGzDecoder::new(file), f),
+    }
+}
+
+fn not_found(name: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("No such member: {name}"))
+}
+
+// Reads only the member named `name`, skipping over the others unread.
+fn read_tar_member(reader: impl Read, name: &str) -> io::Result<Vec<u8>> {
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let entry = entry?;
+        if entry.header().entry_type().is_file() && entry.path()?.to_string_lossy() == name {
+            return read_limited(entry);
+        }
+    }
+    Err(not_found(name))
+}
+
+// The contents of a member, for opening it read-only
+pub fn read_member(kind: Kind, path: &Path, name: &str) -> io::Result<Vec<u8>> {
+    let file = BufReader::new(File::open(path)?);
+
+    match kind {
+        Kind::Zip => {
+            let mut archive = zip::ZipArchive::new(file)?;
+            let member = match archive.by_name(name) {
+                Ok(member) if member.is_file() => member,
+                Ok(_) | Err(zip::result::ZipError::FileNotFound) => return Err(not_found(name)),
+                Err(e) => return Err(e.into()),
+            };
+            read_limited(member)
+        }
+        Kind::Tar => read_tar_member(file, name),
+        Kind::TarGz => read_tar_member(flate2::read::MultiGzDecoder::new(file), name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::{self, TestDir};
+
+    const MEMBERS: [(&str, &str); 3] = [
+        ("src/", ""),
+        ("src/lib.rs", "fn lib() {}\n"),
+        ("README.md", "# readme\n"),
+    ];
+
+    // An archive of each kind, holding `MEMBERS`
+    fn archives(dir: &TestDir) -> Vec<(Kind, PathBuf)> {
+        let tar = test_dir::tar(&MEMBERS);
+        vec![
+            (Kind::Zip, dir.write("a.zip", test_dir::zip(&MEMBERS))),
+            (Kind::Tar, dir.write("a.tar", &tar)),
+            (Kind::TarGz, dir.write("a.tar.gz", test_dir::gzip(&tar))),
+        ]
+    }
+
+    #[test]
+    fn kinds_by_extension() {
+        for (name, kind) in [
+            ("a.zip", Kind::Zip),
+            ("a.tar", Kind::Tar),
+            ("a.tar.gz", Kind::TarGz),
+            ("a.tgz", Kind::TarGz),
+        ] {
+            assert_eq!(Kind::from_path(Path::new(name)), Some(kind));
+        }
+        assert_eq!(Kind::from_path(Path::new("a.gz")), None);
+    }
+
+    #[test]
+    fn member_paths_are_split() {
+        let path = member_path(Path::new("x!/vendor.zip"), "src/lib.rs");
+        assert_eq!(path, Path::new("x!/vendor.zip!/src/lib.rs"));
+
+        // The separator after a directory is not that of an archive.
+        assert_eq!(
+            split_path(path.to_str().unwrap()),
+            Some((Path::new("x!/vendor.zip"), Kind::Zip, "src/lib.rs"))
+        );
+        assert_eq!(split_path("vendor/src/lib.rs"), None);
+    }
+
+    #[test]
+    fn files_are_listed() {
+        let dir = TestDir::new("members");
+        for (kind, path) in archives(&dir) {
+            let mut members = Vec::new();
+            for_each_member(kind, &path, |name, bytes| {
+                members.push((name, String::from_utf8(bytes.unwrap()).unwrap()));
+                true
+            })
+            .unwrap();
+            assert_eq!(
+                members,
+                vec![
+                    ("src/lib.rs".to_string(), "fn lib() {}\n".to_string()),
+                    ("README.md".to_string(), "# readme\n".to_string()),
+                ],
+                "{kind:?}"
+            );
+
+            let mut count = 0;
+            for_each_member(kind, &path, |_, _| {
+                count += 1;
+                false
+            })
+            .unwrap();
+            assert_eq!(count, 1, "{kind:?}");
+        }
+    }
+
+    #[test]
+    fn members_are_read_by_name() {
+        let dir = TestDir::new("member");
+        for (kind, path) in archives(&dir) {
+            assert_eq!(
+                read_member(kind, &path, "README.md").unwrap(),
+                b"# readme\n"
+            );
+            for name in ["src/", "missing.md"] {
+                let e = read_member(kind, &path, name).unwrap_err();
+                assert_eq!(e.kind(), io::ErrorKind::NotFound, "{kind:?} {name}");
+            }
+        }
+    }
+
+    #[test]
+    fn broken_archives_are_errors() {
+        let dir = TestDir::new("broken");
+        let path = dir.write("a.zip", "not a zip");
+        assert!(for_each_member(Kind::Zip, &path, |_, _| true).is_err());
+        assert!(read_member(Kind::Zip, &path, "README.md").is_err());
+    }
+}
@@ -0,0 +1,175 @@
+// Casing styles of identifiers, for the case-preserving replacement.
+//
+// user_id, UserId, USER_ID, userId and user-id all consist of the words "user" and "id".
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Style {
+    // user_id
+    Snake,
+    // USER_ID
+    ScreamingSnake,
+    // user-id
+    Kebab,
+    // userId
+    Camel,
+    // UserId
+    Pascal,
+    // userid
+    Lower,
+    // USERID
+    Upper,
+}
+
+// Splits at non-alphanumeric characters, at lower-to-upper changes (userId), and before the last
+// upper of an acronym followed by a lower (HTTPServer).
+fn split_words(s: &str) -> Vec<&str> {
+    let mut words = Vec::new();
+    let chars: Vec<(usize, char)> = s.char_indices().collect();
+
+    let mut start = None;
+    for (i, &(offset, c)) in chars.iter().enumerate() {
+        if !c.is_alphanumeric() {
+            if let Some(start) = start.take() {
+                words.push(&s[start..offset]);
+            }
+            continue;
+        }
+
+        let Some(word_start) = start else {
+            start = Some(offset);
+            continue;
+        };
+        let prev = chars[i - 1].1;
+        let next = chars.get(i + 1).map(|&(_, c)| c);
+        let boundary = c.is_uppercase()
+            && (prev.is_lowercase()
+                || prev.is_numeric()
+                || (prev.is_uppercase() && next.is_some_and(char::is_lowercase)));
+        if boundary {
+            words.push(&s[word_start..offset]);
+            start = Some(offset);
+        }
+    }
+    if let Some(start) = start {
+        words.push(&s[start..]);
+    }
+
+    words
+}
+
+fn detect(matched: &str) -> Style {
+    let has_lower = matched.chars().any(char::is_lowercase);
+    let has_upper = matched.chars().any(char::is_uppercase);
+
+    if matched.contains('_') {
+        if has_lower {
+            Style::Snake
+        } else {
+            Style::ScreamingSnake
+        }
+    } else if matched.contains('-') {
+        Style::Kebab
+    } else if !has_lower && has_upper {
+        Style::Upper
+    } else if matched.chars().next().is_some_and(char::is_uppercase) {
+        Style::Pascal
+    } else if has_upper {
+        Style::Camel
+    } else {
+        Style::Lower
+    }
+}
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => first
+            .to_uppercase()
+            .chain(chars.flat_map(char::to_lowercase))
+            .collect(),
+        None => String::new(),
+    }
+}
+
+fn apply(style: Style, words: &[&str]) -> String {
+    let lower = || words.iter().map(|word| word.to_lowercase());
+    match style {
+        Style::Snake => lower().collect::<Vec<_>>().join("_"),
+        Style::ScreamingSnake => words
+            .iter()
+            .map(|word| word.to_uppercase())
+            .collect::<Vec<_>>()
+            .join("_"),
+        Style::Kebab => lower().collect::<Vec<_>>().join("-"),
+        Style::Camel => lower()
+            .enumerate()
+            .map(|(i, word)| if i == 0 { word } else { capitalize(&word) })
+            .collect(),
+        Style::Pascal => words.iter().map(|word| capitalize(word)).collect(),
+        Style::Lower => lower().collect(),
+        Style::Upper => words.iter().map(|word| word.to_uppercase()).collect(),
+    }
+}
+
+// The words of the replacement in the style of the matched text.
+pub fn restyle(replacement: &str, matched: &str) -> String {
+    apply(detect(matched), &split_words(replacement))
+}
+
+// A case-insensitive regex matching the words of `pattern` in any of the styles, e.g.
+// user[-_]?id for user_id.
+pub fn variants_pattern(pattern: &str) -> String {
+    let words = split_words(pattern);
+    if words.is_empty() {
+        return regex_syntax::escape(pattern);
+    }
+
+    words
+        .iter()
+        .map(|word| regex_syntax::escape(word))
+        .collect::<Vec<_>>()
+        .join("[-_]?")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restyle_in_each_style() {
+        let cases = [
+            ("user_id", "account_id"),
+            ("USER_ID", "ACCOUNT_ID"),
+            ("user-id", "account-id"),
+            ("userId", "accountId"),
+            ("UserId", "AccountId"),
+            ("userid", "accountid"),
+            ("USERID", "ACCOUNTID"),
+        ];
+        for (matched, expected) in cases {
+            assert_eq!(restyle("account_id", matched), expected, "{matched}");
+            assert_eq!(restyle("AccountId", matched), expected, "{matched}");
+        }
+    }
+
+    #[test]
+    fn split_acronyms_and_numbers() {
+        assert_eq!(split_words("HTTPServer"), ["HTTP", "Server"]);
+        assert_eq!(split_words("utf8Decoder"), ["utf8", "Decoder"]);
+        assert_eq!(split_words("--user__id--"), ["user", "id"]);
+    }
+
+    #[test]
+    fn variants_match_each_style() {
+        use grep::matcher::Matcher;
+
+        let pattern = format!("(?i)^{}$", variants_pattern("user_id"));
+        let variants = grep::regex::RegexMatcher::new(&pattern).unwrap();
+        for matched in [
+            "user_id", "USER_ID", "user-id", "userId", "UserId", "userid", "USERID",
+        ] {
+            assert!(variants.is_match(matched.as_bytes()).unwrap(), "{matched}");
+        }
+        assert!(!variants.is_match(b"user.id").unwrap());
+    }
+}
@@ -0,0 +1,174 @@
+// Decoders of compressed files, searched in place of their contents.
+
+use std::io::{self, BufReader, Read, Write};
+use std::path::Path;
+
+// Same as the largest file walked
+const MAX_DECOMPRESSED_SIZE: u64 = 1_000_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Gzip,
+    Xz,
+    Zstd,
+    Bzip2,
+}
+
+impl Format {
+    // Detected by the extension
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "gz" => Some(Self::Gzip),
+            "xz" => Some(Self::Xz),
+            "zst" => Some(Self::Zstd),
+            "bz2" => Some(Self::Bzip2),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Xz => "xz",
+            Self::Zstd => "zstd",
+            Self::Bzip2 => "bzip2",
+        }
+    }
+}
+
+fn invalid_data(e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+fn too_large() -> io::Error {
+    invalid_data(format!(
+        "Decompressed size exceeds {MAX_DECOMPRESSED_SIZE} bytes"
+    ))
+}
+
+// Reads to the end, failing over the size limit; also used for the members of archives.
+pub fn read_limited(reader: impl Read) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    reader
+        .take(MAX_DECOMPRESSED_SIZE + 1)
+        .read_to_end(&mut bytes)?;
+    if bytes.len() as u64 > MAX_DECOMPRESSED_SIZE {
+        return Err(too_large());
+    }
+    Ok(bytes)
+}
+
+// lzma-rs writes out instead of being read.
+struct LimitedWriter(Vec<u8>);
+
+impl Write for LimitedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if (self.0.len() + buf.len()) as u64 > MAX_DECOMPRESSED_SIZE {
+            return Err(too_large());
+        }
+        self.0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// The whole decompressed contents of the file at `path`
+pub fn read(format: Format, path: &Path) -> io::Result<Vec<u8>> {
+    let file = BufReader::new(std::fs::File::open(path)?);
+
+    match format {
+        // Concatenated gzip files, e.g. appended by `gzip -c >>`, have several members.
+        Format::Gzip => read_limited(flate2::read::MultiGzDecoder::new(file)),
+        Format::Xz => {
+            let mut file = file;
+            let mut out = LimitedWriter(Vec::new());
+            match lzma_rs::xz_decompress(&mut file, &mut out) {
+                Ok(()) => Ok(out.0),
+                Err(lzma_rs::error::Error::IoError(e)) => Err(e),
+                Err(e) => Err(invalid_data(e)),
+            }
+        }
+        Format::Zstd => {
+            read_limited(ruzstd::decoding::StreamingDecoder::new(file).map_err(invalid_data)?)
+        }
+        Format::Bzip2 => read_limited(bzip2::read::MultiBzDecoder::new(file)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::{self, TestDir};
+
+    const TEXT: &[u8] = b"first line\nsecond line\n";
+
+    fn compress(format: Format, bytes: &[u8]) -> Vec<u8> {
+        match format {
+            Format::Gzip => test_dir::gzip(bytes),
+            Format::Xz => {
+                let mut out = Vec::new();
+                lzma_rs::xz_compress(&mut &bytes[..], &mut out).unwrap();
+                out
+            }
+            Format::Zstd => ruzstd::encoding::compress_to_vec(
+                bytes,
+                ruzstd::encoding::CompressionLevel::Fastest,
+            ),
+            Format::Bzip2 => {
+                let mut encoder =
+                    bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::fast());
+                encoder.write_all(bytes).unwrap();
+                encoder.finish().unwrap()
+            }
+        }
+    }
+
+    const FORMATS: [(Format, &str); 4] = [
+        (Format::Gzip, "a.txt.gz"),
+        (Format::Xz, "a.txt.xz"),
+        (Format::Zstd, "a.txt.zst"),
+        (Format::Bzip2, "a.txt.bz2"),
+    ];
+
+    #[test]
+    fn formats_by_extension() {
+        for (format, name) in FORMATS {
+            assert_eq!(Format::from_path(Path::new(name)), Some(format));
+        }
+        assert_eq!(Format::from_path(Path::new("a.txt")), None);
+        assert_eq!(Format::from_path(Path::new("gz")), None);
+    }
+
+    #[test]
+    fn each_format_is_decompressed() {
+        let dir = TestDir::new("decompress");
+        for (format, name) in FORMATS {
+            let path = dir.write(name, compress(format, TEXT));
+            assert_eq!(read(format, &path).unwrap(), TEXT, "{}", format.name());
+        }
+    }
+
+    #[test]
+    fn concatenated_members_are_read() {
+        let dir = TestDir::new("members");
+        for format in [Format::Gzip, Format::Bzip2] {
+            let bytes = [compress(format, b"first\n"), compress(format, b"second\n")].concat();
+            let path = dir.write("a", bytes);
+            assert_eq!(read(format, &path).unwrap(), b"first\nsecond\n");
+        }
+    }
+
+    #[test]
+    fn corrupt_input_is_an_error() {
+        let dir = TestDir::new("corrupt");
+        for (format, name) in FORMATS {
+            let mut bytes = compress(format, TEXT);
+            bytes.truncate(bytes.len() / 2);
+            let path = dir.write(name, bytes);
+            assert!(read(format, &path).is_err(), "{}", format.name());
+        }
+    }
+}
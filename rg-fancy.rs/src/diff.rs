@@ -0,0 +1,221 @@
+use std::ops::Range;
+
+// Same as diff -u
+pub const DEFAULT_CONTEXT: usize = 3;
+
+// The range of a match in the original bytes, and that of its replacement in the replaced ones
+pub type Change = (Range<usize>, Range<usize>);
+
+// A changed range of whole lines; `src` is in the original bytes and `dst` in the replaced ones.
+struct Block {
+    src: Range<usize>,
+    dst: Range<usize>,
+}
+
+// Lines in `bytes`, counting a last line without a terminator
+fn line_count(bytes: &[u8]) -> usize {
+    bytes.split_inclusive(|&b| b == b'\n').count()
+}
+
+// Widens a replaced match to the lines it touches. Text around the match is the same in both, so
+// the widened ranges cover the same lines on either side.
+fn widen(src: &[u8], matched: &Range<usize>, replaced: &Range<usize>) -> Block {
+    let line_start = src[..matched.start]
+        .iter()
+        .rposition(|&b| b == b'\n')
+        .map_or(0, |i| i + 1);
+    let last = if matched.is_empty() {
+        matched.start
+    } else {
+        matched.end - 1
+    };
+    let line_end = src[last..]
+        .iter()
+        .position(|&b| b == b'\n')
+        .map_or(src.len(), |i| last + i + 1)
+        .max(matched.end);
+
+    Block {
+        src: line_start..line_end,
+        dst: replaced.start - (matched.start - line_start)..replaced.end + (line_end - matched.end),
+    }
+}
+
+fn blocks(src: &[u8], dst: &[u8], changes: &[Change]) -> Vec<Block> {
+    let mut blocks: Vec<Block> = Vec::new();
+    for (matched, replaced) in changes {
+        let block = widen(src, matched, replaced);
+        match blocks.last_mut() {
+            Some(last) if block.src.start <= last.src.end => {
+                last.src.end = block.src.end;
+                last.dst.end = block.dst.end;
+            }
+            _ => blocks.push(block),
+        }
+    }
+
+    blocks.retain(|block| src[block.src.clone()] != dst[block.dst.clone()]);
+    blocks
+}
+
+fn push_line(out: &mut Vec<u8>, prefix: u8, line: &[u8]) {
+    out.push(prefix);
+    out.extend_from_slice(line);
+    if !line.ends_with(b"\n") {
+        out.extend_from_slice(b"\n\\ No newline at end of file\n");
+    }
+}
+
+// A unified diff between `src` and `dst`, with `changes` as returned by `rg::replace_bytes`.
+// Returns None if nothing changes.
+pub fn unified(
+    label: &str,
+    src: &[u8],
+    dst: &[u8],
+    changes: &[Change],
+    context: usize,
+) -> Option<String> {
+    let blocks = blocks(src, dst, changes);
+    if blocks.is_empty() {
+        return None;
+    }
+
+    let src_lines: Vec<&[u8]> = src.split_inclusive(|&b| b == b'\n').collect();
+
+    let mut out = format!("--- a/{label}\n+++ b/{label}\n").into_bytes();
+
+    let mut blocks = blocks.iter().peekable();
+    while let Some(first) = blocks.next() {
+        // Blocks closer than twice the context share a hunk.
+        let mut hunk = vec![first];
+        while let Some(&next) = blocks.peek() {
+            let last = hunk[hunk.len() - 1];
+            if line_count(&src[last.src.end..next.src.start]) > context * 2 {
+                break;
+            }
+            hunk.push(next);
+            blocks.next();
+        }
+
+        let first_line = line_count(&src[..first.src.start]);
+        let start = first_line.saturating_sub(context);
+        let new_start = line_count(&dst[..first.dst.start]) - (first_line - start);
+        let last = hunk[hunk.len() - 1];
+        let end = (line_count(&src[..last.src.end]) + context).min(src_lines.len());
+
+        let mut body = Vec::new();
+        let (mut src_len, mut dst_len) = (0, 0);
+        let mut line = start;
+        for block in hunk {
+            let block_line = line_count(&src[..block.src.start]);
+            for unchanged in &src_lines[line..block_line] {
+                push_line(&mut body, b' ', unchanged);
+            }
+            src_len += block_line - line;
+            dst_len += block_line - line;
+
+            let removed: Vec<_> = src[block.src.clone()]
+                .split_inclusive(|&b| b == b'\n')
+                .collect();
+            for removed in &removed {
+                push_line(&mut body, b'-', removed);
+            }
+            for added in dst[block.dst.clone()].split_inclusive(|&b| b == b'\n') {
+                push_line(&mut body, b'+', added);
+                dst_len += 1;
+            }
+            src_len += removed.len();
+            line = block_line + removed.len();
+        }
+        for unchanged in &src_lines[line..end] {
+            push_line(&mut body, b' ', unchanged);
+        }
+        src_len += end - line;
+        dst_len += end - line;
+
+        // An empty side starts at the line before it.
+        let src_start = if src_len == 0 { start } else { start + 1 };
+        let dst_start = if dst_len == 0 {
+            new_start
+        } else {
+            new_start + 1
+        };
+        out.extend_from_slice(
+            format!("@@ -{src_start},{src_len} +{dst_start},{dst_len} @@\n").as_bytes(),
+        );
+        out.append(&mut body);
+    }
+
+    Some(String::from_utf8_lossy(&out).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `src` with each `from` replaced by `to`, and the changes made
+    fn replace(src: &str, from: &str, to: &str) -> (String, Vec<Change>) {
+        let mut dst = String::new();
+        let mut changes = Vec::new();
+        let mut last = 0;
+        for (start, _) in src.match_indices(from) {
+            dst.push_str(&src[last..start]);
+            changes.push((start..start + from.len(), dst.len()..dst.len() + to.len()));
+            dst.push_str(to);
+            last = start + from.len();
+        }
+        dst.push_str(&src[last..]);
+        (dst, changes)
+    }
+
+    fn diff(src: &str, from: &str, to: &str, context: usize) -> Option<String> {
+        let (dst, changes) = replace(src, from, to);
+        unified("a.txt", src.as_bytes(), dst.as_bytes(), &changes, context)
+    }
+
+    #[test]
+    fn changed_lines_with_context() {
+        assert_eq!(
+            diff("1\n2\n3\nfoo\n5\n6\n7\n", "foo", "bar", 2).unwrap(),
+            "--- a/a.txt\n+++ b/a.txt\n@@ -2,5 +2,5 @@\n 2\n 3\n-foo\n+bar\n 5\n 6\n"
+        );
+    }
+
+    #[test]
+    fn near_changes_share_a_hunk() {
+        let src = "x\n2\n3\n4\n5\nx\n7\n8\n9\n10\n11\nx\n";
+        assert_eq!(
+            diff(src, "x", "y", 2).unwrap(),
+            "--- a/a.txt\n+++ b/a.txt\n\
+             @@ -1,8 +1,8 @@\n-x\n+y\n 2\n 3\n 4\n 5\n-x\n+y\n 7\n 8\n\
+             @@ -10,3 +10,3 @@\n 10\n 11\n-x\n+y\n"
+        );
+    }
+
+    #[test]
+    fn matches_spanning_lines() {
+        assert_eq!(
+            diff("a\nb\nc\nd\n", "b\nc", "bc", 0).unwrap(),
+            "--- a/a.txt\n+++ b/a.txt\n@@ -2,2 +2,1 @@\n-b\n-c\n+bc\n"
+        );
+        assert_eq!(
+            diff("a\nb\nc\n", "b\n", "", 0).unwrap(),
+            "--- a/a.txt\n+++ b/a.txt\n@@ -2,1 +1,0 @@\n-b\n"
+        );
+    }
+
+    #[test]
+    fn missing_newline_at_end() {
+        assert_eq!(
+            diff("a\nfoo", "foo", "bar", 1).unwrap(),
+            "--- a/a.txt\n+++ b/a.txt\n@@ -1,2 +1,2 @@\n a\n-foo\n\\ No newline at end of file\n\
+             +bar\n\\ No newline at end of file\n"
+        );
+    }
+
+    #[test]
+    fn unchanged_replacements_have_no_diff() {
+        assert_eq!(diff("a\nfoo\n", "foo", "foo", DEFAULT_CONTEXT), None);
+        assert_eq!(diff("a\nfoo\n", "bar", "baz", DEFAULT_CONTEXT), None);
+    }
+}
@@ -0,0 +1,560 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Distinguishes temporary files written at the same time by this process.
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// FNV-1a, as a hex string since Lua numbers cannot hold 64-bit integers.
+pub fn content_hash(bytes: &[u8]) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &b in bytes {
+        hash ^= u64::from(b);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    format!("{hash:016x}")
+}
+
+// A hidden path next to `path`, unique within this process
+fn tmp_path(path: &Path) -> io::Result<PathBuf> {
+    let dir = path
+        .parent()
+        .ok_or_else(|| io::Error::other("No parent directory"))?;
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+
+    Ok(dir.join(format!(
+        ".{name}.rg-fancy-{}-{}.tmp",
+        std::process::id(),
+        TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    )))
+}
+
+// Writes to a temporary file next to `path`, and renames it over `path`, keeping the permissions.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    // Replaces the target of a symlink rather than the link itself.
+    let path = fs::canonicalize(path)?;
+    let permissions = fs::metadata(&path)?.permissions();
+    let tmp = tmp_path(&path)?;
+
+    let written = (|| {
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&tmp)?;
+        file.write_all(contents)?;
+        file.set_permissions(permissions)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)
+    })();
+    if written.is_err() {
+        fs::remove_file(&tmp).ok();
+    }
+    written
+}
+
+#[derive(Debug)]
+pub enum EditStatus {
+    Edited,
+    // Nothing to change
+    Unchanged,
+    // The file has changed since it was searched.
+    Conflict,
+    Failed(String),
+}
+
+// Why a line edit was not applied
+#[derive(Debug)]
+pub enum LineIssue {
+    // The line on disk differs from the original text.
+    Stale,
+    // The file has fewer lines.
+    Missing,
+    // Another edit of the same line has a different text.
+    Conflict,
+}
+
+#[derive(Debug)]
+pub struct FileEdit {
+    pub path: String,
+    pub status: EditStatus,
+    // Number of replacements or edited lines
+    pub count: usize,
+    // Lines that prevented a line edit, by 1-based line numbers
+    pub issues: Vec<(u64, LineIssue)>,
+}
+
+impl FileEdit {
+    fn new(path: &Path, status: EditStatus, count: usize) -> Self {
+        Self {
+            path: path.to_string_lossy().into_owned(),
+            status,
+            count,
+            issues: Vec::new(),
+        }
+    }
+}
+
+// Reported for a file with changes not yet written in a Neovim buffer, which is left as is.
+pub const UNSAVED: &str = "Modified in a buffer; write it first";
+
+pub fn unsaved_file(path: &Path) -> FileEdit {
+    FileEdit::new(path, EditStatus::Failed(UNSAVED.to_string()), 0)
+}
+
+// Rewrites the file with `edit`, which returns the new content and the number of edits, unless
+// the content hash of the file differs from `expected_hash`.
+pub fn edit_file(
+    path: &Path,
+    expected_hash: &str,
+    edit: impl FnOnce(&[u8]) -> (Vec<u8>, usize),
+) -> FileEdit {
+    let result = |status, count| FileEdit::new(path, status, count);
+
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) => return result(EditStatus::Failed(e.to_string()), 0),
+    };
+    if content_hash(&bytes) != expected_hash {
+        return result(EditStatus::Conflict, 0);
+    }
+
+    let (contents, count) = edit(&bytes);
+    if count == 0 || contents == bytes {
+        return result(EditStatus::Unchanged, 0);
+    }
+
+    match write_atomic(path, &contents) {
+        Ok(()) => result(EditStatus::Edited, count),
+        Err(e) => result(EditStatus::Failed(e.to_string()), 0),
+    }
+}
+
+// An edit of a line as shown in the results, i.e. without trailing whitespace.
+#[derive(Debug)]
+pub struct LineEdit {
+    // 1-based
+    pub line: u64,
+    pub original: String,
+    pub new: String,
+}
+
+// Applies all of the edits, or none of them if any line has an issue. The trailing whitespace and
+// the line terminator of an edited line are kept.
+pub fn edit_lines(path: &Path, edits: &[LineEdit]) -> FileEdit {
+    use std::collections::BTreeMap;
+
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) => return FileEdit::new(path, EditStatus::Failed(e.to_string()), 0),
+    };
+    let lines: Vec<&[u8]> = bytes.split_inclusive(|&b| b == b'\n').collect();
+
+    let mut issues = Vec::new();
+    let mut new_lines: BTreeMap<u64, &str> = BTreeMap::new();
+    for edit in edits {
+        let Some(line) = edit
+            .line
+            .checked_sub(1)
+            .and_then(|idx| lines.get(idx as usize))
+        else {
+            issues.push((edit.line, LineIssue::Missing));
+            continue;
+        };
+        if line.trim_ascii_end() != edit.original.as_bytes() {
+            issues.push((edit.line, LineIssue::Stale));
+            continue;
+        }
+        match new_lines.get(&edit.line) {
+            Some(&new) if new != edit.new => issues.push((edit.line, LineIssue::Conflict)),
+            _ => {
+                new_lines.insert(edit.line, &edit.new);
+            }
+        }
+    }
+
+    if !issues.is_empty() {
+        let mut result = FileEdit::new(path, EditStatus::Conflict, 0);
+        result.issues = issues;
+        return result;
+    }
+
+    let mut contents = Vec::with_capacity(bytes.len());
+    let mut count = 0;
+    for (idx, line) in lines.iter().enumerate() {
+        let content = line.trim_ascii_end();
+        match new_lines.get(&(idx as u64 + 1)) {
+            Some(new) if new.as_bytes() != content => {
+                contents.extend_from_slice(new.as_bytes());
+                contents.extend_from_slice(&line[content.len()..]);
+                count += 1;
+            }
+            _ => contents.extend_from_slice(line),
+        }
+    }
+
+    if count == 0 {
+        return FileEdit::new(path, EditStatus::Unchanged, 0);
+    }
+    match write_atomic(path, &contents) {
+        Ok(()) => FileEdit::new(path, EditStatus::Edited, count),
+        Err(e) => FileEdit::new(path, EditStatus::Failed(e.to_string()), 0),
+    }
+}
+
+#[derive(Debug)]
+pub enum RenameStatus {
+    Ready,
+    // The paths differ only in case, which needs an intermediate name on case-insensitive file
+    // systems.
+    CaseOnly,
+    // Another path is renamed to the same path.
+    Collision,
+    // The new path already exists as another file.
+    Exists,
+    // Either path is empty, absolute, or goes out of the root, also through a symlink.
+    Invalid,
+    Renamed,
+    Failed(String),
+}
+
+// Paths are relative to the root of the search.
+#[derive(Debug)]
+pub struct Rename {
+    pub from: String,
+    pub to: String,
+    pub status: RenameStatus,
+}
+
+fn is_relative_inside(path: &Path) -> bool {
+    path.components().next().is_some()
+        && path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+// Whether the parent directory of `path` stays under the canonical `root` once symlinks are
+// resolved. Directories yet to be created are made under their deepest existing ancestor.
+fn parent_inside(root: &Path, path: &Path) -> bool {
+    let path = root.join(path);
+    for dir in path.ancestors().skip(1) {
+        if fs::symlink_metadata(dir).is_ok() {
+            return fs::canonicalize(dir).is_ok_and(|dir| dir.starts_with(root));
+        }
+    }
+    false
+}
+
+// Whether `to` exists as a file other than `from`. On a case-insensitive file system, a path
+// differing only in case is `from` itself.
+fn is_other_file(from: &Path, to: &Path) -> bool {
+    fs::symlink_metadata(to).is_ok() && !same_file::is_same_file(from, to).unwrap_or(false)
+}
+
+// Checks each of the (from, to) pairs against the others and against the files under `root`.
+pub fn plan_renames(root: &Path, pairs: Vec<(String, String)>) -> Vec<Rename> {
+    use std::collections::HashMap;
+
+    let mut targets: HashMap<&str, usize> = HashMap::new();
+    for (_, to) in &pairs {
+        *targets.entry(to.as_str()).or_default() += 1;
+    }
+    let canonical_root = fs::canonicalize(root);
+    let inside = |path: &str| {
+        let path = Path::new(path);
+        is_relative_inside(path)
+            && canonical_root
+                .as_ref()
+                .is_ok_and(|root| parent_inside(root, path))
+    };
+
+    let statuses: Vec<_> = pairs
+        .iter()
+        .map(|(from, to)| {
+            if !inside(from) || !inside(to) {
+                RenameStatus::Invalid
+            } else if targets[to.as_str()] > 1 {
+                RenameStatus::Collision
+            } else if is_other_file(&root.join(from), &root.join(to)) {
+                RenameStatus::Exists
+            } else if from.to_lowercase() == to.to_lowercase() {
+                RenameStatus::CaseOnly
+            } else {
+                RenameStatus::Ready
+            }
+        })
+        .collect();
+
+    pairs
+        .into_iter()
+        .zip(statuses)
+        .map(|((from, to), status)| Rename { from, to, status })
+        .collect()
+}
+
+fn rename_case_only(from: &Path, to: &Path) -> io::Result<()> {
+    // fs::rename replaces an existing file.
+    if is_other_file(from, to) {
+        return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+    }
+    let tmp = tmp_path(from)?;
+    fs::rename(from, &tmp)?;
+    fs::rename(&tmp, to).inspect_err(|_| {
+        fs::rename(&tmp, from).ok();
+    })
+}
+
+fn rename_into(from: &Path, to: &Path) -> io::Result<()> {
+    if let Some(dir) = to.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // fs::rename replaces an existing file.
+    if fs::symlink_metadata(to).is_ok() {
+        return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+    }
+    fs::rename(from, to)
+}
+
+// Removes the directories emptied by moving `from`, up to `root`.
+fn remove_empty_dirs(root: &Path, from: &Path) {
+    for dir in from.ancestors().skip(1) {
+        if dir == root || !dir.starts_with(root) || fs::remove_dir(dir).is_err() {
+            break;
+        }
+    }
+}
+
+// Plans the renames again, and performs the ones still ready. Parent directories of the new paths
+// are created as needed.
+pub fn apply_renames(root: &Path, pairs: Vec<(String, String)>) -> Vec<Rename> {
+    let mut renames = plan_renames(root, pairs);
+
+    for rename in &mut renames {
+        let from = root.join(&rename.from);
+        let to = root.join(&rename.to);
+
+        let renamed = match rename.status {
+            RenameStatus::Ready => rename_into(&from, &to).map(|()| remove_empty_dirs(root, &from)),
+            RenameStatus::CaseOnly => rename_case_only(&from, &to),
+            _ => continue,
+        };
+        rename.status = match renamed {
+            Ok(()) => RenameStatus::Renamed,
+            Err(e) => RenameStatus::Failed(e.to_string()),
+        };
+    }
+
+    renames
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|&(from, to)| (from.to_string(), to.to_string()))
+            .collect()
+    }
+
+    fn statuses(renames: &[Rename]) -> Vec<String> {
+        renames
+            .iter()
+            .map(|rename| format!("{:?}", rename.status))
+            .collect()
+    }
+
+    fn line_edit(line: u64, original: &str, new: &str) -> LineEdit {
+        LineEdit {
+            line,
+            original: original.to_string(),
+            new: new.to_string(),
+        }
+    }
+
+    #[test]
+    fn plan_renames_statuses() {
+        let dir = TestDir::new("plan");
+        dir.write("a.txt", "a");
+        dir.write("b.txt", "b");
+        dir.write("c.txt", "c");
+        dir.write("d.txt", "d");
+        dir.write("e.txt", "e");
+        dir.write("Case.txt", "case");
+
+        let renames = plan_renames(
+            &dir.0,
+            pairs(&[
+                ("a.txt", "new/a.txt"),
+                ("b.txt", "same.txt"),
+                ("c.txt", "same.txt"),
+                ("d.txt", "e.txt"),
+                ("e.txt", "../e.txt"),
+                ("../outside.txt", "f.txt"),
+                ("/abs.txt", "g.txt"),
+                ("a.txt", ""),
+                ("Case.txt", "case.txt"),
+            ]),
+        );
+
+        assert_eq!(
+            statuses(&renames),
+            [
+                "Ready",
+                "Collision",
+                "Collision",
+                "Exists",
+                "Invalid",
+                "Invalid",
+                "Invalid",
+                "Invalid",
+                "CaseOnly",
+            ]
+        );
+    }
+
+    #[test]
+    fn case_only_rename_keeps_other_file() {
+        let dir = TestDir::new("case");
+        let upper = dir.write("Foo.txt", "UPPER");
+        let lower = dir.write("foo.txt", "lower");
+        // Only on a case-sensitive file system can both exist.
+        if same_file::is_same_file(&upper, &lower).unwrap() {
+            return;
+        }
+
+        let renames = apply_renames(&dir.0, pairs(&[("Foo.txt", "foo.txt")]));
+
+        assert_eq!(statuses(&renames), ["Exists"]);
+        assert_eq!(dir.read("Foo.txt"), "UPPER");
+        assert_eq!(dir.read("foo.txt"), "lower");
+        assert!(rename_case_only(&upper, &lower).is_err());
+        assert_eq!(dir.read("foo.txt"), "lower");
+    }
+
+    #[test]
+    fn case_only_rename() {
+        let dir = TestDir::new("case-only");
+        dir.write("Foo.txt", "foo");
+
+        let renames = apply_renames(&dir.0, pairs(&[("Foo.txt", "foo.txt")]));
+
+        assert_eq!(statuses(&renames), ["Renamed"]);
+        assert_eq!(dir.read("foo.txt"), "foo");
+    }
+
+    #[test]
+    fn apply_renames_stays_inside_root() {
+        let dir = TestDir::new("outside");
+        dir.write("root/a.txt", "a");
+        dir.write("b.txt", "b");
+        dir.write("root/sub/c.txt", "c");
+        let root = dir.0.join("root");
+
+        let renames = apply_renames(
+            &root,
+            pairs(&[
+                ("../b.txt", "b.txt"),
+                ("a.txt", "../a.txt"),
+                ("sub/c.txt", "moved/c.txt"),
+            ]),
+        );
+
+        assert_eq!(statuses(&renames), ["Invalid", "Invalid", "Renamed"]);
+        assert_eq!(dir.read("b.txt"), "b");
+        assert_eq!(dir.read("root/a.txt"), "a");
+        assert_eq!(dir.read("root/moved/c.txt"), "c");
+        // Emptied by the move
+        assert!(!root.join("sub").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn plan_renames_resolves_symlinks() {
+        use std::os::unix::fs::symlink;
+
+        let dir = TestDir::new("symlink");
+        dir.write("root/a.txt", "a");
+        dir.write("root/inner/b.txt", "b");
+        dir.write("outside/c.txt", "c");
+        let root = dir.0.join("root");
+        symlink(dir.0.join("outside"), root.join("out")).unwrap();
+        symlink(root.join("inner"), root.join("in")).unwrap();
+
+        let renames = plan_renames(
+            &root,
+            pairs(&[
+                ("a.txt", "out/a.txt"),
+                ("a.txt", "out/new/a.txt"),
+                ("out/c.txt", "c.txt"),
+                ("inner/b.txt", "in/new/b.txt"),
+            ]),
+        );
+
+        assert_eq!(
+            statuses(&renames),
+            ["Invalid", "Invalid", "Invalid", "Ready"]
+        );
+    }
+
+    #[test]
+    fn edit_lines_keeps_line_endings() {
+        let dir = TestDir::new("edit");
+        let path = dir.write("a.txt", "one  \r\ntwo\nthree");
+
+        let edit = edit_lines(
+            &path,
+            &[
+                line_edit(1, "one", "ONE"),
+                line_edit(3, "three", "THREE"),
+                line_edit(3, "three", "THREE"),
+            ],
+        );
+
+        assert!(matches!(edit.status, EditStatus::Edited));
+        assert_eq!(edit.count, 2);
+        assert_eq!(dir.read("a.txt"), "ONE  \r\ntwo\nTHREE");
+    }
+
+    #[test]
+    fn edit_lines_unchanged() {
+        let dir = TestDir::new("unchanged");
+        let path = dir.write("a.txt", "one\n");
+
+        let edit = edit_lines(&path, &[line_edit(1, "one", "one")]);
+
+        assert!(matches!(edit.status, EditStatus::Unchanged));
+        assert_eq!(dir.read("a.txt"), "one\n");
+    }
+
+    #[test]
+    fn edit_lines_with_issues_edits_nothing() {
+        let dir = TestDir::new("issues");
+        let path = dir.write("a.txt", "one\ntwo\nthree\n");
+
+        let edit = edit_lines(
+            &path,
+            &[
+                line_edit(1, "one", "ONE"),
+                line_edit(2, "changed", "TWO"),
+                line_edit(3, "three", "THREE"),
+                line_edit(3, "three", "3"),
+                line_edit(9, "nine", "NINE"),
+            ],
+        );
+
+        assert!(matches!(edit.status, EditStatus::Conflict));
+        let issues: Vec<_> = edit
+            .issues
+            .iter()
+            .map(|(line, issue)| format!("{line} {issue:?}"))
+            .collect();
+        assert_eq!(issues, ["2 Stale", "3 Conflict", "9 Missing"]);
+        assert_eq!(dir.read("a.txt"), "one\ntwo\nthree\n");
+    }
+}
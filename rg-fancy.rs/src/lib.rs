@@ -1,23 +1,69 @@
+mod archive;
+
+mod case;
+
+mod decompress;
+
+mod diff;
+
+mod edit;
+
 mod rg;
 
 mod rpc;
 
+#[cfg(test)]
+mod test_dir;
+
 use nvim_router::NeovimWriter;
 use nvim_router::RpcArgs;
+use nvim_router::nvim_rs::error::CallError;
 use nvim_router::nvim_rs::{Neovim, Value};
 
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, mpsc};
+
+use std::collections::HashMap;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+const DEFAULT_MAX_SEARCHES: usize = 4;
 
-fn search_results<'a, const CONTEXT_LENGTH: usize>(
+// Number of file batches buffered between a search worker and Neovim.
+const STREAM_BUFFER: usize = 64;
+
+fn search_results<'a>(
     dir: &Path,
     pattern: &str,
     glob: impl Iterator<Item = &'a str>,
+    options: &rg::SearchOptions,
+    cancelled: Arc<AtomicBool>,
+    threads: usize,
 ) -> Value {
-    let Some(results) = rg::search_dir::<CONTEXT_LENGTH>(dir, pattern, glob) else {
-        return Value::Nil;
-    };
-    rpc::to_values::<CONTEXT_LENGTH>(results)
+    match rg::search_dir(dir, pattern, glob, options, cancelled, threads) {
+        Ok(mut results) => {
+            let glob_errors = results.take_glob_errors();
+            let sorted = results.sorted();
+            let binary = results.take_skipped_binary();
+            rpc::to_values(
+                sorted,
+                glob_errors,
+                results.is_cancelled(),
+                results.is_truncated(),
+                binary,
+            )
+        }
+        Err(e) => rpc::search_err_value(e),
+    }
+}
+
+// Options are optional, and default to `rg::SearchOptions::default()` when omitted.
+fn next_search_options(args: &mut RpcArgs) -> Result<rg::SearchOptions, Value> {
+    match args.next_array() {
+        Some(options) => rpc::search_options(&options),
+        None => Ok(Default::default()),
+    }
 }
 
 fn resolve_path(cwd: &str, path: &str) -> PathBuf {
@@ -25,53 +71,569 @@ fn resolve_path(cwd: &str, path: &str) -> PathBuf {
     cwd.join(path)
 }
 
+// The arguments leading each request that searches under a path
+struct SearchArgs {
+    cwd: String,
+    // Resolved against `cwd`
+    path: PathBuf,
+    pattern: String,
+    glob: Vec<Value>,
+}
+
+impl SearchArgs {
+    fn glob(&self) -> impl Iterator<Item = &str> {
+        self.glob.iter().filter_map(Value::as_str)
+    }
+}
+
+fn next_search_args(args: &mut RpcArgs) -> Option<SearchArgs> {
+    let cwd = args.next_string()?;
+    let path = args.next_string()?;
+    let pattern = args.next_string()?;
+    let glob = args.next_array()?;
+
+    Some(SearchArgs {
+        path: resolve_path(&cwd, &path),
+        cwd,
+        pattern,
+        glob,
+    })
+}
+
+// Lines not matching have nothing to replace.
+fn set_replacement(options: &mut rg::SearchOptions, replacement: String) -> Result<(), Value> {
+    if options.invert {
+        return Err(rpc::msg_value("Cannot replace in the invert mode"));
+    }
+    options.replacement = Some(replacement);
+    Ok(())
+}
+
+// Scheduled so that the callbacks never run before the `grep_stream` request returns its id.
+const STREAM_HANDLER: &str = r#"
+local event, id, payload = ...
+vim.schedule(function()
+    require("rg-fancy.rpc").stream.dispatch(event, id, payload)
+end)
+"#;
+
+// { name, lines } of each loaded and modified buffer of a file
+const MODIFIED_BUFFERS: &str = r#"
+local buffers = {}
+for _, buf in ipairs(vim.api.nvim_list_bufs()) do
+    if vim.api.nvim_buf_is_loaded(buf) and vim.bo[buf].modified and vim.bo[buf].buftype == "" then
+        local name = vim.api.nvim_buf_get_name(buf)
+        if name ~= "" then
+            table.insert(buffers, { name, vim.api.nvim_buf_get_lines(buf, 0, -1, false) })
+        end
+    end
+end
+return buffers
+"#;
+
+// The edits not yet written, which searches see in place of the files on disk, and which
+// replacing or diffing files would lose
+async fn modified_buffers<W: NeovimWriter>(neovim: &Neovim<W>) -> Arc<rg::Buffers> {
+    match neovim.exec_lua(MODIFIED_BUFFERS, vec![]).await {
+        Ok(Value::Array(buffers)) => Arc::new(rpc::buffers(&buffers)),
+        _ => Default::default(),
+    }
+}
+
+async fn push_stream<W: NeovimWriter>(
+    neovim: &Neovim<W>,
+    event: &str,
+    id: u64,
+    payload: Value,
+) -> Result<(), Box<CallError>> {
+    neovim
+        .exec_lua(
+            STREAM_HANDLER,
+            vec![Value::from(event), Value::from(id), payload],
+        )
+        .await?;
+    Ok(())
+}
+
+// Pushes a "batch" event per file with matches or errors, and a "done" event with the totals.
+// The search itself runs on a blocking worker, holding `permit` until it finishes.
+async fn stream_results<W: NeovimWriter>(
+    neovim: Neovim<W>,
+    id: u64,
+    mut search_results: rg::RgIter,
+    cancelled: Arc<AtomicBool>,
+    permit: OwnedSemaphorePermit,
+) {
+    let (tx, mut rx) = mpsc::channel(STREAM_BUFFER);
+
+    let worker = tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        let mut totals = rpc::Totals::default();
+        let glob_errors = search_results.take_glob_errors();
+
+        for result in search_results.by_ref() {
+            let Some(batch) = rpc::batch_value(result, &mut totals) else {
+                continue;
+            };
+            if tx.blocking_send(batch).is_err() {
+                break;
+            }
+        }
+
+        totals.into_value(
+            search_results.is_cancelled(),
+            search_results.is_truncated(),
+            search_results.take_skipped_binary(),
+            glob_errors,
+        )
+    });
+
+    while let Some(batch) = rx.recv().await {
+        if push_stream(&neovim, "batch", id, batch).await.is_err() {
+            cancelled.store(true, Ordering::Relaxed);
+            return;
+        }
+    }
+
+    if let Ok(totals) = worker.await {
+        push_stream(&neovim, "done", id, totals).await.ok();
+    }
+}
+
+// Cancellation flags of in-flight searches, keyed by search ids.
+#[derive(Clone, Default)]
+struct Searches {
+    next_id: Arc<AtomicU64>,
+    flags: Arc<Mutex<HashMap<u64, Arc<AtomicBool>>>>,
+}
+
+impl Searches {
+    fn register(&self) -> (u64, Arc<AtomicBool>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let cancelled = Arc::new(AtomicBool::new(false));
+        if let Ok(mut flags) = self.flags.lock() {
+            flags.insert(id, cancelled.clone());
+        }
+        (id, cancelled)
+    }
+
+    fn unregister(&self, id: u64) {
+        if let Ok(mut flags) = self.flags.lock() {
+            flags.remove(&id);
+        }
+    }
+
+    // Cancels every search in flight, e.g. when Neovim is interrupted while waiting for one.
+    fn cancel_all(&self) -> Vec<u64> {
+        let Ok(flags) = self.flags.lock() else {
+            return Vec::new();
+        };
+        for cancelled in flags.values() {
+            cancelled.store(true, Ordering::Relaxed);
+        }
+        flags.keys().copied().collect()
+    }
+
+    fn cancel(&self, id: u64) -> bool {
+        let Ok(flags) = self.flags.lock() else {
+            return false;
+        };
+        if let Some(cancelled) = flags.get(&id) {
+            cancelled.store(true, Ordering::Relaxed);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+// Unregisters the search of a request when the request returns.
+struct Registration<'a> {
+    searches: &'a Searches,
+    id: u64,
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        self.searches.unregister(self.id);
+    }
+}
+
 #[derive(Clone)]
-pub struct NeovimHandler<const CONTEXT_LENGTH: usize>;
+pub struct NeovimHandler {
+    searches: Searches,
+    // Limits the number of searches running at the same time.
+    // Replaced on `configure`; searches already holding a permit keep the old one.
+    workers: Arc<Mutex<Arc<Semaphore>>>,
+    // Walker threads per search; 0 chooses automatically.
+    threads: Arc<AtomicUsize>,
+}
+
+impl NeovimHandler {
+    // Registers a search answered within its request, so that it can be cancelled until then.
+    fn register_search(&self) -> (Registration<'_>, Arc<AtomicBool>) {
+        let (id, cancelled) = self.searches.register();
+        let registration = Registration {
+            searches: &self.searches,
+            id,
+        };
+        (registration, cancelled)
+    }
 
-impl<W: NeovimWriter, const CONTEXT_LENGTH: usize> nvim_router::NeovimHandler<W>
-    for NeovimHandler<CONTEXT_LENGTH>
-{
+    fn workers(&self) -> Arc<Semaphore> {
+        match self.workers.lock() {
+            Ok(workers) => workers.clone(),
+            Err(e) => e.into_inner().clone(),
+        }
+    }
+
+    fn threads(&self) -> usize {
+        self.threads.load(Ordering::Relaxed)
+    }
+
+    // Runs `f` on a blocking worker once one of the `max_searches` permits is free.
+    async fn run_blocking<F>(&self, f: F) -> Value
+    where
+        F: FnOnce() -> Value + Send + 'static,
+    {
+        let Ok(permit) = self.workers().acquire_owned().await else {
+            return Value::Nil;
+        };
+        let value = tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            f()
+        });
+
+        value.await.unwrap_or(Value::Nil)
+    }
+
+    fn configure(&self, options: &[Value]) -> Result<(), Value> {
+        for (key, value) in rpc::option_pairs(options)? {
+            match key {
+                "max_searches" => {
+                    let Some(max) = value.as_u64().filter(|&max| max > 0) else {
+                        return Err(rpc::msg_value(format!(
+                            "max_searches must be a positive integer: {value}"
+                        )));
+                    };
+                    let workers = Arc::new(Semaphore::new(max as usize));
+                    match self.workers.lock() {
+                        Ok(mut current) => *current = workers,
+                        Err(e) => *e.into_inner() = workers,
+                    }
+                }
+                "threads" => {
+                    let Some(threads) = value.as_u64() else {
+                        return Err(rpc::msg_value(format!(
+                            "threads must be a non-negative integer: {value}"
+                        )));
+                    };
+                    self.threads.store(threads as usize, Ordering::Relaxed);
+                }
+                _ => return Err(rpc::msg_value(format!("Unknown option: {key}"))),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<W: NeovimWriter> nvim_router::NeovimHandler<W> for NeovimHandler {
     fn new() -> Self {
-        Self
+        Self {
+            searches: Searches::default(),
+            workers: Arc::new(Mutex::new(Arc::new(Semaphore::new(DEFAULT_MAX_SEARCHES)))),
+            threads: Arc::new(AtomicUsize::new(0)),
+        }
     }
 
     async fn handle_request(
         &self,
         name: &str,
         mut args: RpcArgs,
-        _neovim: Neovim<W>,
+        neovim: Neovim<W>,
     ) -> Result<Value, Value> {
-        if name == "grep" {
-            let Some(cwd) = args.next_string() else {
+        if name == "grep" || name == "replace_preview" || name == "replace_case_preview" {
+            let Some(search) = next_search_args(&mut args) else {
                 return Ok(Value::Nil);
             };
-            let Some(path) = args.next_string() else {
+            let replacement = if name == "grep" {
+                None
+            } else {
+                let Some(replacement) = args.next_string() else {
+                    return Ok(Value::Nil);
+                };
+                Some(replacement)
+            };
+            let mut options = match next_search_options(&mut args) {
+                Ok(options) => options,
+                Err(e) => return Ok(e),
+            };
+            if let Some(replacement) = replacement {
+                if let Err(e) = set_replacement(&mut options, replacement) {
+                    return Ok(e);
+                }
+                options.preserve_case |= name == "replace_case_preview";
+            }
+            options.buffers = modified_buffers(&neovim).await;
+
+            let (_registration, cancelled) = self.register_search();
+            let threads = self.threads();
+            let results = self.run_blocking(move || {
+                let SearchArgs { path, pattern, .. } = &search;
+                search_results(path, pattern, search.glob(), &options, cancelled, threads)
+            });
+
+            Ok(results.await)
+        } else if name == "apply_replace" {
+            let Some(cwd) = args.next_string() else {
                 return Ok(Value::Nil);
             };
             let Some(pattern) = args.next_string() else {
                 return Ok(Value::Nil);
             };
-            let Some(glob) = args.next_array() else {
+            let Some(replacement) = args.next_string() else {
+                return Ok(Value::Nil);
+            };
+            let Some(files) = args.next_array() else {
+                return Ok(Value::Nil);
+            };
+            let mut options = match next_search_options(&mut args) {
+                Ok(options) => options,
+                Err(e) => return Ok(e),
+            };
+            if let Err(e) = set_replacement(&mut options, replacement) {
+                return Ok(e);
+            }
+
+            let files = match rpc::file_hashes(&files) {
+                Ok(files) => files
+                    .into_iter()
+                    .map(|(path, hash)| (resolve_path(&cwd, path), hash.to_string()))
+                    .collect::<Vec<_>>(),
+                Err(e) => return Ok(e),
+            };
+            options.buffers = modified_buffers(&neovim).await;
+
+            let edits =
+                self.run_blocking(
+                    move || match rg::replace_files(&pattern, &options, &files) {
+                        Ok(edits) => {
+                            Value::Array(edits.into_iter().map(rpc::file_edit_value).collect())
+                        }
+                        Err(e) => rpc::search_err_value(e),
+                    },
+                );
+
+            Ok(edits.await)
+        } else if name == "apply_line_edits" {
+            let Some(cwd) = args.next_string() else {
+                return Ok(Value::Nil);
+            };
+            let Some(edits) = args.next_array() else {
+                return Ok(Value::Nil);
+            };
+
+            let files = match rpc::line_edits(&edits) {
+                Ok(files) => files
+                    .into_iter()
+                    .map(|(path, edits)| (resolve_path(&cwd, path), edits))
+                    .collect::<Vec<_>>(),
+                Err(e) => return Ok(e),
+            };
+            let buffers = modified_buffers(&neovim).await;
+
+            let edits = self.run_blocking(move || {
+                let edits = files.iter().map(|(path, edits)| {
+                    if buffers.contains(path) {
+                        return rpc::file_edit_value(edit::unsaved_file(path));
+                    }
+                    rpc::file_edit_value(edit::edit_lines(path, edits))
+                });
+                Value::Array(edits.collect())
+            });
+
+            Ok(edits.await)
+        } else if name == "diff_replace" {
+            let Some(search) = next_search_args(&mut args) else {
+                return Ok(Value::Nil);
+            };
+            let Some(replacement) = args.next_string() else {
+                return Ok(Value::Nil);
+            };
+            let Some(diff_options) = args.next_array() else {
+                return Ok(Value::Nil);
+            };
+            let context = match rpc::diff_context(&diff_options) {
+                Ok(context) => context,
+                Err(e) => return Ok(e),
+            };
+            let mut options = match next_search_options(&mut args) {
+                Ok(options) => options,
+                Err(e) => return Ok(e),
+            };
+            if let Err(e) = set_replacement(&mut options, replacement) {
+                return Ok(e);
+            }
+            options.buffers = modified_buffers(&neovim).await;
+
+            let (_registration, cancelled) = self.register_search();
+            let threads = self.threads();
+            let diffs = self.run_blocking(move || {
+                let SearchArgs {
+                    cwd, path, pattern, ..
+                } = &search;
+                let format = rg::DiffFormat {
+                    base: cwd.as_ref(),
+                    context,
+                };
+                let glob = search.glob();
+                match rg::diff_dir(path, pattern, glob, &options, format, &cancelled, threads) {
+                    Ok(diffs) => rpc::diff_results_value(diffs),
+                    Err(e) => rpc::search_err_value(e),
+                }
+            });
+
+            Ok(diffs.await)
+        } else if name == "rename_paths" {
+            let Some(search) = next_search_args(&mut args) else {
+                return Ok(Value::Nil);
+            };
+            let Some(replacement) = args.next_string() else {
+                return Ok(Value::Nil);
+            };
+            let options = match next_search_options(&mut args) {
+                Ok(options) => options,
+                Err(e) => return Ok(e),
+            };
+
+            let threads = self.threads();
+            let plan = self.run_blocking(move || {
+                let SearchArgs { path, pattern, .. } = &search;
+                let glob = search.glob();
+                match rg::rename_paths(path, pattern, &replacement, glob, &options, threads) {
+                    Ok(plan) => rpc::rename_plan_value(plan),
+                    Err(e) => rpc::search_err_value(e),
+                }
+            });
+
+            Ok(plan.await)
+        } else if name == "apply_renames" {
+            let Some(cwd) = args.next_string() else {
+                return Ok(Value::Nil);
+            };
+            let Some(path) = args.next_string() else {
+                return Ok(Value::Nil);
+            };
+            let Some(renames) = args.next_array() else {
                 return Ok(Value::Nil);
             };
 
+            let renames = match rpc::rename_pairs(&renames) {
+                Ok(renames) => renames,
+                Err(e) => return Ok(e),
+            };
             let path = resolve_path(&cwd, &path);
-            let glob = glob.iter().filter_map(|glob| glob.as_str());
 
-            Ok(search_results::<CONTEXT_LENGTH>(&path, &pattern, glob))
+            let renames = self.run_blocking(move || {
+                let renames = edit::apply_renames(&path, renames);
+                Value::Array(renames.into_iter().map(rpc::rename_value).collect())
+            });
+
+            Ok(renames.await)
+        } else if name == "grep_stream" {
+            let Some(search) = next_search_args(&mut args) else {
+                return Ok(Value::Nil);
+            };
+            let mut options = match next_search_options(&mut args) {
+                Ok(options) => options,
+                Err(e) => return Ok(e),
+            };
+            options.buffers = modified_buffers(&neovim).await;
+
+            let (id, cancelled) = self.searches.register();
+            let threads = self.threads();
+            let SearchArgs { path, pattern, .. } = &search;
+            let glob = search.glob();
+            let results =
+                match rg::search_dir(path, pattern, glob, &options, cancelled.clone(), threads) {
+                    Ok(results) => results,
+                    Err(e) => {
+                        self.searches.unregister(id);
+                        return Ok(rpc::search_err_value(e));
+                    }
+                };
+
+            let searches = self.searches.clone();
+            let workers = self.workers();
+            tokio::spawn(async move {
+                if let Ok(permit) = workers.acquire_owned().await {
+                    stream_results(neovim, id, results, cancelled, permit).await;
+                }
+                searches.unregister(id);
+            });
+
+            Ok(Value::from(id))
+        } else if name == "read_member" {
+            let Some(cwd) = args.next_string() else {
+                return Ok(Value::Nil);
+            };
+            let Some(path) = args.next_string() else {
+                return Ok(Value::Nil);
+            };
+
+            let Some((archive, kind, member)) = archive::split_path(&path) else {
+                return Ok(rpc::msg_value(format!(
+                    "Not a member of an archive: {path}"
+                )));
+            };
+            let archive = Path::new(&cwd).join(archive);
+            let member = member.to_string();
+
+            let lines = self.run_blocking(move || {
+                rpc::member_value(archive::read_member(kind, &archive, &member), &path)
+            });
+
+            Ok(lines.await)
+        } else if name == "validate" {
+            let Some(search) = next_search_args(&mut args) else {
+                return Ok(Value::Nil);
+            };
+            let options = match next_search_options(&mut args) {
+                Ok(options) => options,
+                Err(e) => return Ok(e),
+            };
+
+            let diagnostics = rg::validate(&search.path, &search.pattern, search.glob(), &options);
+            Ok(rpc::diagnostics_value(diagnostics))
+        } else if name == "cancel" {
+            // Without ids, every search in flight is cancelled.
+            let cancelled = match args.next_array() {
+                Some(ids) => ids
+                    .iter()
+                    .filter_map(Value::as_u64)
+                    .filter(|&id| self.searches.cancel(id))
+                    .map(Value::from)
+                    .collect(),
+                None => self
+                    .searches
+                    .cancel_all()
+                    .into_iter()
+                    .map(Value::from)
+                    .collect(),
+            };
+
+            Ok(Value::Array(cancelled))
+        } else if name == "configure" {
+            let Some(options) = args.next_array() else {
+                return Ok(Value::Nil);
+            };
+
+            match self.configure(&options) {
+                Ok(()) => Ok(Value::Nil),
+                Err(e) => Ok(e),
+            }
         } else {
             Ok(Value::Nil)
         }
     }
 }
-
-pub type NeovimHandler1 = NeovimHandler<1>;
-pub type NeovimHandler2 = NeovimHandler<2>;
-pub type NeovimHandler3 = NeovimHandler<3>;
-pub type NeovimHandler4 = NeovimHandler<4>;
-pub type NeovimHandler5 = NeovimHandler<5>;
-pub type NeovimHandler6 = NeovimHandler<6>;
-pub type NeovimHandler7 = NeovimHandler<7>;
-pub type NeovimHandler8 = NeovimHandler<8>;
-pub type NeovimHandler9 = NeovimHandler<9>;
-pub type NeovimHandler10 = NeovimHandler<10>;
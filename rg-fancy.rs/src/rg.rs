@@ -1,12 +1,34 @@
+use grep::matcher::Matcher;
 use grep::regex::{self, RegexMatcher};
 use grep::searcher::{Searcher, Sink, SinkContext, SinkMatch};
 
-use std::path::Path;
+use encoding_rs::Encoding;
 
-fn build_walker<'a>(
+use crate::archive;
+use crate::case;
+use crate::decompress;
+use crate::diff;
+use crate::edit::{self, FileEdit, Rename};
+
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+
+// Number of searched files buffered between the walker threads and `RgIter`.
+const RESULTS_BUFFER: usize = 256;
+
+const DEFAULT_CONTEXT_LENGTH: usize = 2;
+
+// threads: 0 chooses the number of threads automatically
+fn build_walker(
     path: &Path,
-    glob: impl Iterator<Item = &'a str>,
-) -> impl Iterator<Item = Result<ignore::DirEntry, ignore::Error>> {
+    overrides: Option<ignore::overrides::Override>,
+    threads: usize,
+) -> ignore::WalkParallel {
     use ignore::WalkBuilder;
 
     let mut builder = WalkBuilder::new(path);
@@ -14,53 +36,618 @@ fn build_walker<'a>(
     builder
         .follow_links(true)
         .max_filesize(Some(1_000_000_000))
-        .threads(1)
+        .threads(threads)
         .hidden(false);
+    if let Some(overrides) = overrides {
+        builder.overrides(overrides);
+    }
+
+    builder.build_parallel()
+}
+
+// A glob rejected by the override builder; index is its position in the given globs.
+#[derive(Debug)]
+pub struct GlobError {
+    pub index: usize,
+    pub glob: String,
+    pub msg: String,
+}
+
+// Invalid globs are skipped, and returned along with the overrides of the valid ones.
+fn build_overrides<'a>(
+    path: &Path,
+    glob: impl Iterator<Item = &'a str>,
+) -> (Option<ignore::overrides::Override>, Vec<GlobError>) {
     let mut overrides = ignore::overrides::OverrideBuilder::new(path);
     overrides.add("!**/.git").ok();
-    for glob in glob {
-        overrides.add(glob).ok();
+
+    let mut errors = Vec::new();
+    for (index, glob) in glob.enumerate() {
+        if let Err(e) = overrides.add(glob) {
+            errors.push(GlobError {
+                index,
+                glob: glob.to_string(),
+                msg: e.to_string(),
+            });
+        }
     }
-    if let Ok(overrides) = overrides.build() {
-        builder.overrides(overrides);
+
+    (overrides.build().ok(), errors)
+}
+
+fn is_file(entry: &ignore::DirEntry) -> bool {
+    entry.file_type().is_some_and(|ft| ft.is_file())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaseMode {
+    Smart,
+    Sensitive,
+    Insensitive,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Greed {
+    Greedy,
+    Lazy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+    Rust,
+    // Available with the pcre2 feature
+    Pcre2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatternMode {
+    Regex,
+    // The pattern and `literals` are searched as fixed strings.
+    Literal,
+}
+
+// What to do with files containing NUL
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryMode {
+    // Left out of the results, and counted as skipped
+    Skip,
+    // Searched until NUL is found; the searcher reads in chunks, so matches in the chunk with the
+    // first NUL are left out as well.
+    Quit,
+    // Searched as text, with NUL shown as '.'
+    Convert,
+}
+
+#[derive(Debug, Clone)]
+pub struct SearchOptions {
+    pub case: CaseMode,
+    pub word: bool,
+    pub invert: bool,
+    pub greed: Greed,
+    // Whether a match may span multiple lines
+    pub multi_line: bool,
+    pub dot_matches_new_line: bool,
+    // Whether lines are terminated by \r\n as well as \n
+    pub crlf: bool,
+    // Whether an invalid glob fails the search, instead of being skipped
+    pub strict_globs: bool,
+    pub engine: Engine,
+    pub mode: PatternMode,
+    // Searched along with the pattern in the literal mode
+    pub literals: Vec<String>,
+    // Template of replace_preview, e.g. "$1" or "${name}"; not a user option
+    pub replacement: Option<String>,
+    // Match the words of the pattern in any casing style, and write the replacement in the style
+    // of each match.
+    pub preserve_case: bool,
+    // Number of lines shown before/after each match
+    pub before_context: usize,
+    pub after_context: usize,
+    // Matches kept per file and across files, unlimited if None. Further matches in a file are only
+    // counted, and the matches across files are cut at the cap in path order.
+    pub max_matches_per_file: Option<usize>,
+    pub max_matches: Option<usize>,
+    pub binary: BinaryMode,
+    // Whether the paths of the files skipped as binary are reported, not only their count
+    pub binary_paths: bool,
+    // Label of the encoding files are decoded from, e.g. "shift_jis"; a BOM takes precedence.
+    // Without it, files without a BOM are searched as UTF-8.
+    pub encoding: Option<String>,
+    // Pairs of a glob and an encoding label, which take precedence over `encoding` for the files
+    // matching the glob; the first match wins.
+    pub encodings: Vec<(String, String)>,
+    // Whether .gz, .xz, .zst and .bz2 files are searched in their decompressed contents
+    pub decompress: bool,
+    // Whether the members of .zip, .tar and .tar.gz files are searched, each as a file named like
+    // vendor.zip!/src/lib.rs
+    pub archives: bool,
+    // Contents of the modified Neovim buffers, searched in place of their files; not a user option
+    pub buffers: Arc<Buffers>,
+}
+
+impl Default for SearchOptions {
+    fn default() -> Self {
+        Self {
+            case: CaseMode::Smart,
+            word: false,
+            invert: false,
+            greed: Greed::Lazy,
+            multi_line: false,
+            dot_matches_new_line: false,
+            crlf: false,
+            strict_globs: false,
+            engine: Engine::Rust,
+            mode: PatternMode::Regex,
+            literals: Vec::new(),
+            replacement: None,
+            preserve_case: false,
+            before_context: DEFAULT_CONTEXT_LENGTH,
+            after_context: DEFAULT_CONTEXT_LENGTH,
+            max_matches_per_file: None,
+            max_matches: None,
+            binary: BinaryMode::Skip,
+            binary_paths: false,
+            encoding: None,
+            encodings: Vec::new(),
+            decompress: false,
+            archives: false,
+            buffers: Default::default(),
+        }
     }
+}
 
-    builder.build().filter(|entry| {
-        entry
-            .as_ref()
-            .is_ok_and(|entry| entry.file_type().is_some_and(|ft| ft.is_file()))
-    })
+impl SearchOptions {
+    fn replacement(&self) -> Option<Replacement<'_>> {
+        self.replacement.as_deref().map(|template| Replacement {
+            template: template.as_bytes(),
+            preserve_case: self.preserve_case,
+        })
+    }
+
+    // Options of the regex matching the case variants of the pattern
+    fn case_variants(&self) -> Self {
+        Self {
+            case: CaseMode::Insensitive,
+            mode: PatternMode::Regex,
+            preserve_case: false,
+            ..self.clone()
+        }
+    }
 }
 
-fn build_matcher(pattern: &str) -> Result<RegexMatcher, regex::Error> {
+// The pattern followed by the non-empty `literals` in the literal mode, and empty otherwise.
+// Spans refer to the literals by their indices in this list.
+fn literal_patterns(pattern: &str, options: &SearchOptions) -> Vec<String> {
+    if options.mode != PatternMode::Literal || options.preserve_case {
+        return Vec::new();
+    }
+
+    let literals = options
+        .literals
+        .iter()
+        .filter(|literal| !literal.is_empty());
+    std::iter::once(pattern)
+        .chain(literals.map(String::as_str))
+        .map(str::to_string)
+        .collect()
+}
+
+fn build_matcher(pattern: &str, options: &SearchOptions) -> Result<RegexMatcher, regex::Error> {
     use grep::regex::RegexMatcherBuilder;
 
+    if options.preserve_case {
+        return build_matcher(&case::variants_pattern(pattern), &options.case_variants());
+    }
+
     let mut builder = RegexMatcherBuilder::new();
 
     builder
-        .case_smart(true)
+        .case_smart(options.case == CaseMode::Smart)
+        .case_insensitive(options.case == CaseMode::Insensitive)
+        .word(options.word)
+        // build_literals parses the literals as regexes unless fixed_strings is set
+        .fixed_strings(options.mode == PatternMode::Literal)
         // true: ^/$ matches the beginning/end of lines
         // false: ^/$ matches the beginning/end of the input
         .multi_line(true)
-        // true: a* greedy, a*? lazy
-        // false: a* lazy, a*? greedy
-        .swap_greed(true)
+        // true: a* lazy, a*? greedy
+        // false: a* greedy, a*? lazy
+        .swap_greed(options.greed == Greed::Lazy)
         .ignore_whitespace(false)
-        .line_terminator(Some(b'\n'))
-        .dot_matches_new_line(false);
+        .dot_matches_new_line(options.dot_matches_new_line);
+    // crlf also sets \r\n as the line terminator.
+    if options.crlf {
+        builder.crlf(true);
+    }
+    // A line terminator keeps matches within lines, which lets the searcher skip to the matched
+    // lines, but rejects patterns with \n. As in ripgrep, it is set only without multi_line.
+    if options.multi_line {
+        builder.line_terminator(None);
+    } else if !options.crlf {
+        builder.line_terminator(Some(b'\n'));
+    }
 
-    builder.build(pattern)
+    if options.mode == PatternMode::Literal {
+        builder.build_literals(&literal_patterns(pattern, options))
+    } else {
+        builder.build(pattern)
+    }
 }
 
-fn build_searcher<const CONTEXT_LENGTH: usize>() -> Searcher {
-    use grep::searcher::SearcherBuilder;
+// Smart case as in grep-pcre2, but decided on the user's patterns rather than on what is built from
+// them, in which e.g. the (?U) prepended for the lazy greed would count as an uppercase letter.
+#[cfg(feature = "pcre2")]
+fn pcre2_caseless(patterns: &[&str], options: &SearchOptions) -> bool {
+    // In a regex, the letter after a backslash is an escape, e.g. \S or \W.
+    fn has_uppercase_literal(pattern: &str) -> bool {
+        let mut chars = pattern.chars();
+        while let Some(c) = chars.next() {
+            if c == '\\' {
+                chars.next();
+            } else if c.is_uppercase() {
+                return true;
+            }
+        }
+        false
+    }
+
+    match options.case {
+        CaseMode::Sensitive => false,
+        CaseMode::Insensitive => true,
+        CaseMode::Smart if options.mode == PatternMode::Literal => !patterns
+            .iter()
+            .any(|pattern| pattern.chars().any(char::is_uppercase)),
+        CaseMode::Smart => !patterns
+            .iter()
+            .any(|pattern| has_uppercase_literal(pattern)),
+    }
+}
+
+// Length of what grep-pcre2 wraps a pattern in before compiling it: (?:...), and (?<!\w)(?:...)
+// around that for `word`.
+#[cfg(feature = "pcre2")]
+fn pcre2_wrapper_len(options: &SearchOptions) -> usize {
+    let word = if options.word { r"(?<!\w)(?:".len() } else { 0 };
+    word + "(?:".len()
+}
+
+#[cfg(feature = "pcre2")]
+fn build_pcre2_matcher(
+    pattern: &str,
+    options: &SearchOptions,
+) -> Result<grep::pcre2::RegexMatcher, PatternError> {
+    use grep::pcre2::RegexMatcherBuilder;
+
+    if options.preserve_case {
+        return build_pcre2_matcher(&case::variants_pattern(pattern), &options.case_variants());
+    }
+
+    let literals = literal_patterns(pattern, options);
+    let patterns: Vec<&str> = if options.mode == PatternMode::Literal {
+        literals.iter().map(String::as_str).collect()
+    } else {
+        vec![pattern]
+    };
+
+    let mut builder = RegexMatcherBuilder::new();
 
-    let mut builder = SearcherBuilder::new();
     builder
-        .after_context(CONTEXT_LENGTH)
-        .before_context(CONTEXT_LENGTH)
+        .case_smart(false)
+        .caseless(pcre2_caseless(&patterns, options))
+        .word(options.word)
+        .fixed_strings(options.mode == PatternMode::Literal)
         .multi_line(true)
+        .dotall(options.dot_matches_new_line)
+        .crlf(options.crlf)
+        .utf(true)
+        .ucp(true)
+        .jit_if_available(true);
+
+    let wrapper_len = pcre2_wrapper_len(options);
+    if options.mode == PatternMode::Literal {
+        // Escaped by grep-pcre2, so no (?U) is prepended.
+        builder
+            .build_many(&literals)
+            .map_err(|e| PatternError::pcre2(e, pattern, wrapper_len))
+    } else if options.greed == Greed::Lazy {
+        // PCRE2 has no swap_greed; (?U) makes a* lazy and a*? greedy.
+        builder
+            .build(&format!("(?U){pattern}"))
+            .map_err(|e| PatternError::pcre2(e, pattern, wrapper_len + "(?U)".len()))
+    } else {
+        builder
+            .build(pattern)
+            .map_err(|e| PatternError::pcre2(e, pattern, wrapper_len))
+    }
+}
+
+// Builds the matcher of `options.engine` as `$matcher`, and evaluates `$body` with it; `$body` is
+// compiled for each engine, so it may be generic over the matcher. An invalid pattern is returned
+// as `SearchError::Pattern`.
+macro_rules! with_matcher {
+    ($pattern:expr, $options:expr, |$matcher:ident| $body:expr) => {
+        match $options.engine {
+            Engine::Rust => match build_matcher($pattern, $options) {
+                Ok($matcher) => $body,
+                Err(e) => Err(SearchError::Pattern(PatternError::from(
+                    e, $pattern, $options,
+                ))),
+            },
+            #[cfg(feature = "pcre2")]
+            Engine::Pcre2 => match build_pcre2_matcher($pattern, $options) {
+                Ok($matcher) => $body,
+                Err(e) => Err(SearchError::Pattern(e)),
+            },
+            #[cfg(not(feature = "pcre2"))]
+            Engine::Pcre2 => Err(SearchError::Pattern(PatternError::pcre2_unavailable())),
+        }
+    };
+}
+
+// None if the pattern is valid for the engine.
+fn pattern_error(pattern: &str, options: &SearchOptions) -> Option<PatternError> {
+    match with_matcher!(pattern, options, |_matcher| Ok(())) {
+        Err(SearchError::Pattern(e)) => Some(e),
+        _ => None,
+    }
+}
+
+// An invalid pattern; span is the byte range in the pattern, if known.
+#[derive(Debug)]
+pub struct PatternError {
+    pub msg: String,
+    pub kind: String,
+    pub span: Option<(usize, usize)>,
+}
+
+impl PatternError {
+    // grep wraps the pattern before parsing it, so its message and span do not refer to the
+    // pattern itself. The pattern is parsed again here with the same settings as `build_matcher`.
+    fn from(e: regex::Error, pattern: &str, options: &SearchOptions) -> Self {
+        use regex_syntax::ast::parse::ParserBuilder;
+        use regex_syntax::hir::translate::TranslatorBuilder;
+
+        // e.g. "NestLimitExceeded(250)" -> "NestLimitExceeded"
+        fn kind_name(kind: &impl std::fmt::Debug) -> String {
+            let kind = format!("{kind:?}");
+            let end = kind
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .unwrap_or(kind.len());
+            kind[..end].to_string()
+        }
+
+        fn span(span: &regex_syntax::ast::Span) -> Option<(usize, usize)> {
+            Some((span.start.offset, span.end.offset))
+        }
+
+        if options.mode == PatternMode::Regex {
+            let parsed = ParserBuilder::new()
+                .ignore_whitespace(false)
+                .build()
+                .parse(pattern);
+            let ast = match parsed {
+                Ok(ast) => ast,
+                Err(e) => {
+                    return Self {
+                        msg: e.kind().to_string(),
+                        kind: kind_name(e.kind()),
+                        span: span(e.span()),
+                    };
+                }
+            };
+
+            let translated = TranslatorBuilder::new()
+                .utf8(false)
+                .case_insensitive(options.case == CaseMode::Insensitive)
+                .multi_line(true)
+                .dot_matches_new_line(options.dot_matches_new_line)
+                .crlf(options.crlf)
+                .swap_greed(options.greed == Greed::Lazy)
+                .build()
+                .translate(pattern, &ast);
+            if let Err(e) = translated {
+                return Self {
+                    msg: e.kind().to_string(),
+                    kind: kind_name(e.kind()),
+                    span: span(e.span()),
+                };
+            }
+        }
+
+        Self {
+            msg: e.to_string(),
+            kind: kind_name(e.kind()),
+            span: None,
+        }
+    }
+
+    // PCRE2 reports only the offset where it gave up, e.g.
+    // "PCRE2: error compiling pattern at offset 3: missing closing parenthesis".
+    // prefix_len is the length of what precedes the pattern in the compiled regex.
+    #[cfg(feature = "pcre2")]
+    fn pcre2(e: grep::pcre2::Error, pattern: &str, prefix_len: usize) -> Self {
+        let msg = e.to_string();
+        let offset = msg.split_once("at offset ").and_then(|(_, rest)| {
+            let digits = rest.split(|c: char| !c.is_ascii_digit()).next()?;
+            digits.parse::<usize>().ok()
+        });
+        let span = offset.map(|offset| {
+            let offset = offset.saturating_sub(prefix_len).min(pattern.len());
+            (offset, offset)
+        });
+
+        Self {
+            msg,
+            kind: "Pcre2".to_string(),
+            span,
+        }
+    }
+
+    #[cfg(not(feature = "pcre2"))]
+    fn pcre2_unavailable() -> Self {
+        Self {
+            msg: "PCRE2 is not available; build rg-fancy.rs with the pcre2 feature".to_string(),
+            kind: "Unsupported".to_string(),
+            span: None,
+        }
+    }
+}
+
+// None for an unknown label; "replacement" is rejected, as it decodes everything to U+FFFD.
+pub fn encoding_for_label(label: &str) -> Option<&'static Encoding> {
+    Encoding::for_label_no_replacement(label.as_bytes())
+}
+
+// Contents of unsaved buffers, keyed by the canonical paths of their files, so that they match
+// the walked paths however the search path is written.
+#[derive(Debug, Default)]
+pub struct Buffers {
+    paths: HashMap<PathBuf, Vec<u8>>,
+    // Inode numbers of the files, by which most walked files are passed over without resolving
+    // their paths
+    inodes: HashSet<u64>,
+}
+
+impl Buffers {
+    // Buffers without a file on disk are never walked, so are left out.
+    pub fn insert(&mut self, path: &Path, bytes: Vec<u8>) {
+        let Ok(path) = std::fs::canonicalize(path) else {
+            return;
+        };
+        #[cfg(unix)]
+        if let Ok(metadata) = std::fs::metadata(&path) {
+            use std::os::unix::fs::MetadataExt;
+            self.inodes.insert(metadata.ino());
+        }
+        self.paths.insert(path, bytes);
+    }
+
+    // Whether `path` has unsaved changes in a buffer
+    pub fn contains(&self, path: &Path) -> bool {
+        !self.paths.is_empty()
+            && std::fs::canonicalize(path).is_ok_and(|path| self.paths.contains_key(&path))
+    }
+
+    fn get(&self, entry: &ignore::DirEntry) -> Option<&[u8]> {
+        if self.paths.is_empty() {
+            return None;
+        }
+        // The inode number comes from the directory listing, so is that of the link itself for a
+        // followed symlink.
+        let other_inode = entry
+            .ino()
+            .is_some_and(|ino| !entry.path_is_symlink() && !self.inodes.contains(&ino));
+        if other_inode {
+            return None;
+        }
+        let path = std::fs::canonicalize(entry.path()).ok()?;
+        self.paths.get(&path).map(Vec::as_slice)
+    }
+}
+
+// The encodings of `SearchOptions`, resolved for each file
+struct Encodings {
+    globs: Vec<(ignore::overrides::Override, &'static Encoding)>,
+    default: Option<&'static Encoding>,
+}
+
+impl Encodings {
+    fn new(dir: &Path, options: &SearchOptions) -> Result<Self, SearchError> {
+        use ignore::overrides::OverrideBuilder;
+
+        let encoding = |label: &str| {
+            encoding_for_label(label)
+                .ok_or_else(|| SearchError::Encoding(format!("Unknown encoding: {label}")))
+        };
+
+        let default = options.encoding.as_deref().map(encoding).transpose()?;
+        let globs = options
+            .encodings
+            .iter()
+            .map(|(glob, label)| {
+                let overrides = OverrideBuilder::new(dir)
+                    .add(glob)
+                    .and_then(|builder| builder.build())
+                    .map_err(|e| SearchError::Encoding(format!("Invalid glob {glob}: {e}")))?;
+                Ok((overrides, encoding(label)?))
+            })
+            .collect::<Result<_, SearchError>>()?;
+
+        Ok(Self { globs, default })
+    }
+
+    // Index of the glob matching `path`, or globs.len() for the default
+    fn index(&self, path: &Path) -> usize {
+        self.globs
+            .iter()
+            .position(|(glob, _)| glob.matched(path, false).is_whitelist())
+            .unwrap_or(self.globs.len())
+    }
+
+    fn get(&self, index: usize) -> Option<&'static Encoding> {
+        self.globs
+            .get(index)
+            .map(|&(_, encoding)| encoding)
+            .or(self.default)
+    }
+
+    // A searcher for each index
+    fn searchers(&self, options: &SearchOptions) -> Vec<FileSearcher> {
+        (0..=self.globs.len())
+            .map(|index| {
+                let encoding = self.get(index);
+                FileSearcher {
+                    searcher: build_searcher(options, encoding),
+                    encoding,
+                }
+            })
+            .collect()
+    }
+}
+
+// A searcher along with the encoding it is given
+struct FileSearcher {
+    searcher: Searcher,
+    encoding: Option<&'static Encoding>,
+}
+
+impl FileSearcher {
+    // The encoding of a file starting with `head`; a BOM takes precedence over the given encoding,
+    // as in the searcher.
+    fn sniff(&self, head: &[u8]) -> Option<&'static Encoding> {
+        Encoding::for_bom(head)
+            .map(|(encoding, _)| encoding)
+            .or(self.encoding)
+    }
+}
+
+fn build_searcher(options: &SearchOptions, encoding: Option<&'static Encoding>) -> Searcher {
+    use grep::matcher::LineTerminator;
+    use grep::searcher::{BinaryDetection, SearcherBuilder};
+
+    let binary = match options.binary {
+        BinaryMode::Skip | BinaryMode::Quit => BinaryDetection::quit(b'\x00'),
+        // The NUL of a converted file is found while reading it ahead. Converting it to a line
+        // terminator would shift the numbers of the lines after it.
+        BinaryMode::Convert => BinaryDetection::none(),
+    };
+
+    let mut builder = SearcherBuilder::new();
+    builder
+        .after_context(options.after_context)
+        .before_context(options.before_context)
+        .multi_line(options.multi_line)
+        .invert_match(options.invert)
+        .binary_detection(binary)
+        .bom_sniffing(true)
         .stop_on_nonmatch(false);
+    if let Some(encoding) = encoding {
+        // Names of encodings are also their labels.
+        builder.encoding(grep::searcher::Encoding::new(encoding.name()).ok());
+    }
+    if options.crlf {
+        builder.line_terminator(LineTerminator::crlf());
+    }
 
     builder.build()
 }
@@ -69,6 +656,219 @@ fn path_to_string(path: &Path) -> String {
     path.to_string_lossy().into_owned()
 }
 
+// A part of a submatch lying on a single line of `RgResult::matched`.
+// A submatch spanning several lines is split into one span per line.
+//
+// start/end: byte offsets in the line
+// start_char/end_char: offsets in UTF-8 characters
+// start_col/end_col: display columns
+#[derive(Debug, Clone)]
+pub struct Span {
+    pub line: usize,
+    pub start: usize,
+    pub end: usize,
+    pub start_char: usize,
+    pub end_char: usize,
+    pub start_col: usize,
+    pub end_col: usize,
+    // Index of the matched literal in the literal mode
+    pub literal: Option<usize>,
+}
+
+impl Span {
+    fn new(line_idx: usize, line: &[u8], start: usize, end: usize) -> Self {
+        use unicode_width::UnicodeWidthStr;
+
+        let before = String::from_utf8_lossy(&line[..start]);
+        let inner = String::from_utf8_lossy(&line[start..end]);
+
+        let start_char = before.chars().count();
+        let start_col = before.width();
+
+        Self {
+            line: line_idx,
+            start: before.len(),
+            end: before.len() + inner.len(),
+            start_char,
+            end_char: start_char + inner.chars().count(),
+            start_col,
+            end_col: start_col + inner.width(),
+            literal: None,
+        }
+    }
+}
+
+// A line as shown. NUL, searched as is in the convert binary mode, is masked by '.', which keeps
+// the offsets and the columns of spans.
+fn display_bytes(line: &[u8]) -> Cow<'_, [u8]> {
+    if line.contains(&0) {
+        Cow::Owned(
+            line.iter()
+                .map(|&b| if b == 0 { b'.' } else { b })
+                .collect(),
+        )
+    } else {
+        Cow::Borrowed(line)
+    }
+}
+
+fn display_line(line: &[u8]) -> String {
+    String::from_utf8_lossy(&display_bytes(line.trim_ascii_end())).into_owned()
+}
+
+// Which of the literals `matched` came from; the exact one is preferred over the case-insensitive
+// one, since the matcher may ignore cases.
+fn literal_index(literals: &[String], matched: &[u8]) -> Option<usize> {
+    if literals.is_empty() {
+        return None;
+    }
+
+    literals
+        .iter()
+        .position(|literal| literal.as_bytes() == matched)
+        .or_else(|| {
+            let matched = String::from_utf8_lossy(matched).to_lowercase();
+            literals
+                .iter()
+                .position(|literal| literal.to_lowercase() == matched)
+        })
+}
+
+// Splits byte ranges in `bytes` into spans per line, each paired with the index of its range.
+// An empty range is kept as an empty span, while a non-empty one is dropped if it covers only
+// line terminators.
+fn line_spans(bytes: &[u8], ranges: &[Range<usize>]) -> Vec<(usize, Span)> {
+    let mut spans = Vec::new();
+    let mut offset = 0;
+    for (line_idx, line) in bytes.split_inclusive(|&b| b == b'\n').enumerate() {
+        let content = display_bytes(line.trim_ascii_end());
+        let line_end = offset + line.len();
+
+        for (i, range) in ranges.iter().enumerate() {
+            let overlaps = if range.is_empty() {
+                offset <= range.start && range.start < line_end
+            } else {
+                offset < range.end && range.start < line_end
+            };
+            if !overlaps {
+                continue;
+            }
+            let start = range.start.saturating_sub(offset).min(content.len());
+            let end = (range.end - offset).min(content.len());
+            if start < end || range.is_empty() {
+                spans.push((i, Span::new(line_idx, &content, start, end)));
+            }
+        }
+
+        offset = line_end;
+    }
+
+    spans
+}
+
+fn match_spans<M: Matcher>(matcher: &M, literals: &[String], bytes: &[u8]) -> Vec<Span> {
+    let mut matches = Vec::new();
+    matcher
+        .find_iter(bytes, |m| {
+            if !m.is_empty() {
+                matches.push(m.start()..m.end());
+            }
+            true
+        })
+        .ok();
+
+    line_spans(bytes, &matches)
+        .into_iter()
+        .map(|(i, mut span)| {
+            span.literal = literal_index(literals, &bytes[matches[i].clone()]);
+            span
+        })
+        .collect()
+}
+
+// Matched lines with the matches replaced; spans are the replacements in `lines`.
+#[derive(Debug)]
+pub struct Replaced {
+    pub lines: Vec<String>,
+    pub spans: Vec<Span>,
+}
+
+#[derive(Clone, Copy)]
+struct Replacement<'a> {
+    template: &'a [u8],
+    // The template is restyled after each match instead of expanded.
+    preserve_case: bool,
+}
+
+// Replaces every match in `bytes`, including empty ones, expanding $1 and ${name} in the template.
+// Returns the replaced bytes and the changes.
+fn replace_bytes<M: Matcher>(
+    matcher: &M,
+    replacement: Replacement<'_>,
+    bytes: &[u8],
+) -> (Vec<u8>, Vec<diff::Change>) {
+    use grep::matcher::Captures;
+
+    let template = String::from_utf8_lossy(replacement.template);
+
+    let mut dst = Vec::new();
+    let mut replaced = Vec::new();
+    let mut last = 0;
+
+    if let Ok(mut caps) = matcher.new_captures() {
+        matcher
+            .captures_iter(bytes, &mut caps, |caps| {
+                let Some(m) = caps.get(0) else {
+                    return true;
+                };
+                // e.g. ^ after the last line terminator, which begins no line
+                if m.start() == bytes.len() && bytes.ends_with(b"\n") {
+                    return false;
+                }
+                dst.extend_from_slice(&bytes[last..m.start()]);
+                let start = dst.len();
+                if replacement.preserve_case {
+                    let matched = String::from_utf8_lossy(&bytes[m]);
+                    dst.extend_from_slice(case::restyle(&template, &matched).as_bytes());
+                } else {
+                    caps.interpolate(
+                        |name| matcher.capture_index(name),
+                        bytes,
+                        replacement.template,
+                        &mut dst,
+                    );
+                }
+                replaced.push((m.start()..m.end(), start..dst.len()));
+                last = m.end();
+                true
+            })
+            .ok();
+    }
+    dst.extend_from_slice(&bytes[last..]);
+
+    (dst, replaced)
+}
+
+fn replace_matches<M: Matcher>(
+    matcher: &M,
+    replacement: Replacement<'_>,
+    bytes: &[u8],
+) -> Replaced {
+    let (dst, replaced) = replace_bytes(matcher, replacement, bytes);
+    let replaced: Vec<_> = replaced.into_iter().map(|(_, range)| range).collect();
+
+    let lines = dst
+        .split_inclusive(|&b| b == b'\n')
+        .map(display_line)
+        .collect();
+    let spans = line_spans(&dst, &replaced)
+        .into_iter()
+        .map(|(_, span)| span)
+        .collect();
+
+    Replaced { lines, spans }
+}
+
 //
 // line_idx - 3: before[2]
 // line_idx - 2: before[1]
@@ -78,23 +878,16 @@ fn path_to_string(path: &Path) -> String {
 // line_idx + 2: after[1]
 // line_idx + 3: after[2]
 //
+// before.len() and after.len() are the before/after context lengths of the search.
 #[derive(Debug)]
-pub struct RgResult<const CONTEXT_LENGTH: usize> {
+pub struct RgResult {
     pub line_idx: Option<u64>,
-    pub before: [Option<String>; CONTEXT_LENGTH],
-    pub after: [Option<String>; CONTEXT_LENGTH],
+    pub before: Vec<Option<String>>,
+    pub after: Vec<Option<String>>,
     pub matched: Option<Vec<String>>,
-}
-
-impl<const CONTEXT_LENGTH: usize> Default for RgResult<CONTEXT_LENGTH> {
-    fn default() -> Self {
-        Self {
-            line_idx: None,
-            before: [const { None }; CONTEXT_LENGTH],
-            after: [const { None }; CONTEXT_LENGTH],
-            matched: None,
-        }
-    }
+    pub spans: Vec<Span>,
+    // With a replacement
+    pub replaced: Option<Replaced>,
 }
 
 pub struct RgErr {
@@ -107,15 +900,46 @@ impl RgErr {
     }
 }
 
-impl<const CONTEXT_LENGTH: usize> RgResult<CONTEXT_LENGTH> {
-    fn update_matched(&mut self, src: &SinkMatch<'_>) {
+impl RgResult {
+    fn new(before_len: usize, after_len: usize) -> Self {
+        Self {
+            line_idx: None,
+            before: vec![None; before_len],
+            after: vec![None; after_len],
+            matched: None,
+            spans: Vec::new(),
+            replaced: None,
+        }
+    }
+
+    // A result whose nearest before-context is `context`
+    fn from_context(before_len: usize, after_len: usize, context: String) -> Self {
+        let mut res = Self::new(before_len, after_len);
+        if let Some(last) = res.before.last_mut() {
+            *last = Some(context);
+        }
+        res
+    }
+
+    // matcher: None for the lines not matching in the invert mode, which have no spans
+    fn update_matched<M: Matcher>(
+        &mut self,
+        src: &SinkMatch<'_>,
+        matcher: Option<&M>,
+        literals: &[String],
+        replacement: Option<Replacement<'_>>,
+    ) {
         self.line_idx = src.line_number();
 
-        let lines = src
-            .lines()
-            .map(|line| String::from_utf8_lossy(line.trim_ascii_end()).into_owned())
-            .collect();
+        let lines = src.lines().map(display_line).collect();
         self.matched = Some(lines);
+
+        let Some(matcher) = matcher else {
+            return;
+        };
+        self.spans = match_spans(matcher, literals, src.bytes());
+        self.replaced =
+            replacement.map(|replacement| replace_matches(matcher, replacement, src.bytes()));
     }
 
     // ctxlen - this_none_end = this_none_end
@@ -123,7 +947,7 @@ impl<const CONTEXT_LENGTH: usize> RgResult<CONTEXT_LENGTH> {
         // self.before
         // [ None, ..., None, Some 1, ..., Some K ]
         // 0..this_none_end: None
-        // this_none_end..CONTEXT_LENGTH: Some
+        // this_none_end..before.len(): Some
         let this_none_end = self.before_none_end();
         if this_none_end == 0 {
             return;
@@ -133,7 +957,7 @@ impl<const CONTEXT_LENGTH: usize> RgResult<CONTEXT_LENGTH> {
         // prev.after
         // [ Some 1', ..., Some K', None, ..., None ]
         // 0..that_none_start: Some
-        // that_none_start..CONTEXT_LENGTH: None
+        // that_none_start..after.len(): None
         let that_none_start = prev.after_none_start();
         let that_some_range = 0..that_none_start;
 
@@ -151,7 +975,7 @@ impl<const CONTEXT_LENGTH: usize> RgResult<CONTEXT_LENGTH> {
         // [ Some K', ..., Some 1', None, ..., None, Some 1, ..., Some K ]
         // 0..that_none_start: Some
         // that_none_start..this_none_end: None
-        // this_none_end..CONTEXT_LENGTH: Some
+        // this_none_end..before.len(): Some
         for i in that_some_range {
             self.before[that_none_start - i - 1] = prev.after[i].clone();
         }
@@ -185,43 +1009,43 @@ impl<const CONTEXT_LENGTH: usize> RgResult<CONTEXT_LENGTH> {
     // self.before
     // [ None, ..., None, Some 1, ..., Some K ]
     // 0..none_end: None
-    // none_end..CONTEXT_LENGTH: Some
+    // none_end..before.len(): Some
     fn before_none_end(&self) -> usize {
-        CONTEXT_LENGTH
-            - self
-                .before
-                .iter()
-                .rev()
-                .enumerate()
-                .find_map(|(i, item)| if item.is_some() { None } else { Some(i) })
-                .unwrap_or(CONTEXT_LENGTH)
+        let len = self.before.len();
+        len - self
+            .before
+            .iter()
+            .rev()
+            .enumerate()
+            .find_map(|(i, item)| if item.is_some() { None } else { Some(i) })
+            .unwrap_or(len)
     }
 
     // self.after
     // [ Some 1, ..., Some K, None, ..., None ]
     // 0..none_start: Some
-    // none_start..CONTEXT_LENGTH: None
+    // none_start..after.len(): None
     fn after_none_start(&self) -> usize {
         self.after
             .iter()
             .enumerate()
             .find_map(|(i, item)| if item.is_some() { None } else { Some(i) })
-            .unwrap_or(CONTEXT_LENGTH)
+            .unwrap_or(self.after.len())
     }
 
     fn append_to_before(&mut self, context: String) {
         let idx = self.before_none_end();
-        if idx == 0 {
-            self.before[CONTEXT_LENGTH - 1] = Some(context);
-            self.before.rotate_right(1);
-        } else {
+        if idx > 0 {
             self.before[idx - 1] = Some(context);
+        } else if let Some(last) = self.before.last_mut() {
+            *last = Some(context);
+            self.before.rotate_right(1);
         }
     }
 
     fn append_to_after(&mut self, context: String) -> Result<(), ()> {
         let idx = self.after_none_start();
-        if idx == CONTEXT_LENGTH {
+        if idx == self.after.len() {
             Err(())
         } else {
             self.after[idx] = Some(context);
@@ -234,35 +1058,158 @@ impl<const CONTEXT_LENGTH: usize> RgResult<CONTEXT_LENGTH> {
         self.before.rotate_left(idx);
     }
 
-    fn is_matched(&self) -> bool {
-        self.matched.is_some()
+    fn is_matched(&self) -> bool {
+        self.matched.is_some()
+    }
+
+    fn context(ctx: &SinkContext<'_>) -> String {
+        display_line(ctx.bytes())
+    }
+}
+
+#[derive(Debug)]
+pub struct RgResults {
+    inner: Vec<RgResult>,
+    path: String,
+    before_len: usize,
+    after_len: usize,
+    // Content hash of the searched file, with a replacement
+    hash: Option<String>,
+    // Matched results kept, and those skipped over `max_matches_per_file`
+    matches: usize,
+    more: u64,
+    // Whether the search of the file stopped early, at `max_matches` or on a cancel
+    stopped: bool,
+    // Offset of the first NUL found, and whether the file is left out for it
+    binary: Option<u64>,
+    skipped: bool,
+    // The encoding decoded from, if any
+    encoding: Option<&'static Encoding>,
+    // The format decompressed from, with `decompress`
+    decompressed: Option<decompress::Format>,
+    // The path of the archive and the name of the member, with `archives`
+    archive: Option<(String, String)>,
+    // Whether searched in a modified buffer instead of the file
+    unsaved: bool,
+}
+
+struct RgSink<'m, M> {
+    // None in the invert mode
+    matcher: Option<&'m M>,
+    literals: &'m [String],
+    replacement: Option<Replacement<'m>>,
+    cancelled: &'m AtomicBool,
+    max_matches_per_file: Option<usize>,
+    binary: BinaryMode,
+    results: RgResults,
+}
+
+struct LastResults<'a> {
+    last: Option<&'a mut RgResult>,
+    second_last: Option<&'a mut RgResult>,
+}
+
+impl RgResults {
+    fn from_path(path: &Path, options: &SearchOptions) -> Self {
+        Self {
+            inner: Default::default(),
+            path: path_to_string(path),
+            before_len: options.before_context,
+            after_len: options.after_context,
+            hash: None,
+            matches: 0,
+            more: 0,
+            stopped: false,
+            binary: None,
+            skipped: false,
+            encoding: None,
+            decompressed: None,
+            archive: None,
+            unsaved: false,
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn hash(&self) -> Option<&str> {
+        self.hash.as_deref()
+    }
+
+    // Matches not listed because of `max_matches_per_file`
+    pub fn more(&self) -> u64 {
+        self.more
+    }
+
+    // Offset of the first NUL in a file searched despite it
+    pub fn binary(&self) -> Option<u64> {
+        self.binary
+    }
+
+    pub fn encoding(&self) -> Option<&'static str> {
+        self.encoding.map(Encoding::name)
+    }
+
+    pub fn decompressed(&self) -> Option<&'static str> {
+        self.decompressed.map(decompress::Format::name)
+    }
+
+    pub fn archive(&self) -> Option<(&str, &str)> {
+        self.archive
+            .as_ref()
+            .map(|(archive, member)| (archive.as_str(), member.as_str()))
+    }
+
+    pub fn is_unsaved(&self) -> bool {
+        self.unsaved
     }
 
-    fn context(ctx: &SinkContext<'_>) -> String {
-        String::from_utf8_lossy(ctx.bytes().trim_ascii_end()).into_owned()
+    // Whether the file is decoded from other than UTF-8
+    fn is_decoded(&self) -> bool {
+        self.encoding
+            .is_some_and(|encoding| encoding != encoding_rs::UTF_8)
     }
-}
 
-#[derive(Debug)]
-pub struct RgResults<const CONTEXT_LENGTH: usize> {
-    inner: Vec<RgResult<CONTEXT_LENGTH>>,
-    path: String,
-}
+    // Drops the results of a file containing NUL.
+    fn skip_binary(&mut self, offset: u64) {
+        self.inner.clear();
+        self.matches = 0;
+        self.more = 0;
+        self.hash = None;
+        self.binary = Some(offset);
+        self.skipped = true;
+    }
 
-struct LastResults<'a, const CONTEXT_LENGTH: usize> {
-    last: Option<&'a mut RgResult<CONTEXT_LENGTH>>,
-    second_last: Option<&'a mut RgResult<CONTEXT_LENGTH>>,
-}
+    // Keeps the first `kept` matches, counting the others as not listed.
+    fn truncate_matches(&mut self, kept: usize) {
+        let mut matched = 0;
+        let end = self.inner.iter().position(|result| {
+            matched += usize::from(result.is_matched());
+            matched > kept
+        });
+        if let Some(end) = end {
+            self.inner.truncate(end);
+        }
+        self.pop_unmatched();
+        self.more += (self.matches - kept) as u64;
+        self.matches = kept;
+        self.stopped = true;
+        self.hash = None;
+    }
 
-impl<const CONTEXT_LENGTH: usize> RgResults<CONTEXT_LENGTH> {
-    fn from_path(path: &Path) -> Self {
-        Self {
-            inner: Default::default(),
-            path: path_to_string(path),
+    // Drops the before context gathered for a match that is not kept.
+    fn pop_unmatched(&mut self) {
+        if self.inner.last().is_some_and(|last| !last.is_matched()) {
+            self.inner.pop();
         }
     }
 
-    fn last_two_mut(&mut self) -> LastResults<'_, CONTEXT_LENGTH> {
+    fn new_result_from_context(&self, context: String) -> RgResult {
+        RgResult::from_context(self.before_len, self.after_len, context)
+    }
+
+    fn last_two_mut(&mut self) -> LastResults<'_> {
         if let Some((last, rest)) = self.inner.split_last_mut() {
             let second_last = rest.last_mut();
             LastResults {
@@ -277,12 +1224,7 @@ impl<const CONTEXT_LENGTH: usize> RgResults<CONTEXT_LENGTH> {
         }
     }
 
-    fn split_last_mut(
-        &mut self,
-    ) -> (
-        Option<&mut RgResult<CONTEXT_LENGTH>>,
-        &mut [RgResult<CONTEXT_LENGTH>],
-    ) {
+    fn split_last_mut(&mut self) -> (Option<&mut RgResult>, &mut [RgResult]) {
         if let Some((last, rest)) = self.inner.split_last_mut() {
             (Some(last), rest)
         } else {
@@ -290,10 +1232,11 @@ impl<const CONTEXT_LENGTH: usize> RgResults<CONTEXT_LENGTH> {
         }
     }
 
-    fn update_after(target: &mut [RgResult<CONTEXT_LENGTH>], src: &[String]) {
+    fn update_after(target: &mut [RgResult], src: &[String]) {
         for res in target.iter_mut().rev() {
+            let len = res.after.len();
             let idx = res.after_none_start();
-            if idx == CONTEXT_LENGTH {
+            if idx == len {
                 return;
             }
 
@@ -303,7 +1246,7 @@ impl<const CONTEXT_LENGTH: usize> RgResults<CONTEXT_LENGTH> {
                 }
             };
 
-            if idx + src.len() <= CONTEXT_LENGTH {
+            if idx + src.len() <= len {
                 clone_from_src(&mut res.after[idx..(idx + src.len())], src);
             } else {
                 clone_from_src(&mut res.after[idx..], src);
@@ -311,11 +1254,11 @@ impl<const CONTEXT_LENGTH: usize> RgResults<CONTEXT_LENGTH> {
         }
     }
 
-    fn push(&mut self, item: RgResult<CONTEXT_LENGTH>) {
+    fn push(&mut self, item: RgResult) {
         self.inner.push(item);
     }
 
-    pub fn into_raw(self) -> (String, Vec<RgResult<CONTEXT_LENGTH>>) {
+    pub fn into_raw(self) -> (String, Vec<RgResult>) {
         (self.path, self.inner)
     }
 }
@@ -419,38 +1362,55 @@ impl<const CONTEXT_LENGTH: usize> RgResults<CONTEXT_LENGTH> {
 // (3) <- [ [Some(1), Some(2)], [Some(3), None], [None, None] ]
 // context 4
 // (4) <- [ [Some(1), Some(2)], [Some(3), Some(4)], [None, None] ]
-impl<const CONTEXT_LENGTH: usize> Sink for RgResults<CONTEXT_LENGTH> {
+impl<M: Matcher> Sink for RgSink<'_, M> {
     type Error = std::io::Error;
 
     fn matched(&mut self, _searcher: &Searcher, mat: &SinkMatch<'_>) -> Result<bool, Self::Error> {
-        let last_two = self.last_two_mut();
+        if self.cancelled.load(Ordering::Relaxed) {
+            self.results.stopped = true;
+            return Ok(false);
+        }
+
+        // Further matches are only counted.
+        if self
+            .max_matches_per_file
+            .is_some_and(|max| self.results.matches >= max)
+        {
+            self.results.pop_unmatched();
+            self.results.more += 1;
+            return Ok(true);
+        }
+        self.results.matches += 1;
+
+        let (before_len, after_len) = (self.results.before_len, self.results.after_len);
+        let last_two = self.results.last_two_mut();
 
         if let Some(last) = last_two.last {
             if last.is_matched() {
-                let mut res = RgResult::default();
+                let mut res = RgResult::new(before_len, after_len);
                 res.copy_before_from_prev(last);
-                res.update_matched(mat);
+                res.update_matched(mat, self.matcher, self.literals, self.replacement);
 
-                self.push(res);
+                self.results.push(res);
 
-                if let (Some(last), rest) = self.split_last_mut() {
-                    Self::update_after(
+                if let (Some(last), rest) = self.results.split_last_mut() {
+                    RgResults::update_after(
                         rest,
                         last.matched.as_ref().map(AsRef::as_ref).unwrap_or_default(),
                     );
                 }
             } else if let Some(second_last) = last_two.second_last {
                 last.copy_before_from_prev(second_last);
-                last.update_matched(mat);
+                last.update_matched(mat, self.matcher, self.literals, self.replacement);
             } else {
                 last.align_before();
-                last.update_matched(mat);
+                last.update_matched(mat, self.matcher, self.literals, self.replacement);
             }
         } else {
-            let mut res = RgResult::default();
-            res.update_matched(mat);
+            let mut res = RgResult::new(before_len, after_len);
+            res.update_matched(mat, self.matcher, self.literals, self.replacement);
 
-            self.push(res);
+            self.results.push(res);
         }
 
         Ok(true)
@@ -461,81 +1421,1100 @@ impl<const CONTEXT_LENGTH: usize> Sink for RgResults<CONTEXT_LENGTH> {
         _searcher: &Searcher,
         context: &SinkContext<'_>,
     ) -> Result<bool, Self::Error> {
-        let context = RgResult::<CONTEXT_LENGTH>::context(context);
+        if self.cancelled.load(Ordering::Relaxed) {
+            self.results.stopped = true;
+            return Ok(false);
+        }
+        // Lines after a skipped match are not the context of any kept match.
+        if self.results.more > 0 {
+            return Ok(true);
+        }
 
-        let last_two = self.last_two_mut();
+        let context = RgResult::context(context);
+
+        let last_two = self.results.last_two_mut();
 
         if let Some(last) = last_two.last {
             if last.is_matched() {
                 if last.append_to_after(context.clone()).is_err() {
-                    let mut res = RgResult::default();
-                    res.before[CONTEXT_LENGTH - 1] = Some(context);
+                    let res = self.results.new_result_from_context(context);
 
-                    self.push(res);
+                    self.results.push(res);
                 } else {
-                    let (_, rest) = self.split_last_mut();
-                    Self::update_after(rest, &[context]);
+                    let (_, rest) = self.results.split_last_mut();
+                    RgResults::update_after(rest, &[context]);
                 }
             } else {
                 last.append_to_before(context);
             }
         } else {
-            let mut res = RgResult::default();
-            res.before[CONTEXT_LENGTH - 1] = Some(context);
+            let res = self.results.new_result_from_context(context);
 
-            self.push(res);
+            self.results.push(res);
         }
 
         Ok(true)
     }
+
+    fn binary_data(
+        &mut self,
+        _searcher: &Searcher,
+        binary_byte_offset: u64,
+    ) -> Result<bool, Self::Error> {
+        match self.binary {
+            BinaryMode::Skip => {
+                self.results.skip_binary(binary_byte_offset);
+                Ok(false)
+            }
+            BinaryMode::Quit => {
+                self.results.binary = Some(binary_byte_offset);
+                Ok(false)
+            }
+            BinaryMode::Convert => Ok(true),
+        }
+    }
+}
+
+// Searches the contents of a file read ahead, e.g. to be hashed or decompressed.
+fn search_bytes<M: Matcher>(
+    matcher: &M,
+    searcher: &mut FileSearcher,
+    bytes: &[u8],
+    printer: &mut RgSink<'_, M>,
+) -> std::io::Result<()> {
+    printer.results.encoding = searcher.sniff(bytes);
+    // The searcher looks for NUL only in the beginning of a slice and in matches. Decoded files,
+    // e.g. UTF-16 ones, are left to the searcher.
+    let nul = if printer.results.is_decoded() {
+        None
+    } else {
+        bytes.iter().position(|&b| b == 0).map(|i| i as u64)
+    };
+    match (nul, printer.binary) {
+        (Some(offset), BinaryMode::Skip) => {
+            printer.results.skip_binary(offset);
+            Ok(())
+        }
+        _ => {
+            printer.results.binary = nul;
+            searcher.searcher.search_slice(matcher, bytes, printer)
+        }
+    }
+}
+
+// The first bytes of a file, long enough for a BOM
+fn read_head(path: &Path) -> Vec<u8> {
+    use std::io::Read;
+
+    let mut head = Vec::new();
+    if let Ok(file) = std::fs::File::open(path) {
+        file.take(3).read_to_end(&mut head).ok();
+    }
+    head
+}
+
+pub type FileResult = Result<(RgResults, Option<RgErr>), RgErr>;
+
+// What `search_file` searches
+enum Source<'a> {
+    File(&'a Path),
+    // A file with a modified buffer, searched in the lines of the buffer
+    Buffer(&'a Path, &'a [u8]),
+    // A member of an archive, read ahead while listing the archive
+    Member {
+        archive: &'a Path,
+        name: String,
+        bytes: std::io::Result<Vec<u8>>,
+    },
+}
+
+// Searches a file on disk, decompressed or read ahead to be hashed if needed.
+fn search_path<M: Matcher>(
+    matcher: &M,
+    searcher: &mut FileSearcher,
+    options: &SearchOptions,
+    path: &Path,
+    printer: &mut RgSink<'_, M>,
+) -> std::io::Result<()> {
+    let compressed = options
+        .decompress
+        .then(|| decompress::Format::from_path(path))
+        .flatten();
+    if let Some(format) = compressed {
+        printer.results.decompressed = Some(format);
+        decompress::read(format, path)
+            .and_then(|bytes| search_bytes(matcher, searcher, &bytes, printer))
+    } else if printer.replacement.is_some() || options.binary == BinaryMode::Convert {
+        // Searches the same bytes as hashed, so that apply_replace can tell whether the file has
+        // changed since. Converted files are read ahead for their NUL, which is searched as is.
+        std::fs::read(path).and_then(|bytes| {
+            if printer.replacement.is_some() {
+                printer.results.hash = Some(edit::content_hash(&bytes));
+            }
+            search_bytes(matcher, searcher, &bytes, printer)
+        })
+    } else {
+        let searched = searcher.searcher.search_path(matcher, path, &mut *printer);
+        // Only files with results are reported, so sniffed only then.
+        if !printer.results.inner.is_empty() {
+            printer.results.encoding = searcher.sniff(&read_head(path));
+        }
+        searched
+    }
+}
+
+fn search_file<M: Matcher>(
+    matcher: &M,
+    literals: &[String],
+    searcher: &mut FileSearcher,
+    options: &SearchOptions,
+    cancelled: &AtomicBool,
+    source: Source<'_>,
+) -> FileResult {
+    let results = match &source {
+        Source::File(path) => RgResults::from_path(path, options),
+        Source::Buffer(path, _) => {
+            let mut results = RgResults::from_path(path, options);
+            results.unsaved = true;
+            results
+        }
+        Source::Member { archive, name, .. } => {
+            let mut results = RgResults::from_path(&archive::member_path(archive, name), options);
+            results.archive = Some((path_to_string(archive), name.clone()));
+            results
+        }
+    };
+    let mut printer = RgSink {
+        matcher: (!options.invert).then_some(matcher),
+        literals,
+        replacement: options.replacement(),
+        cancelled,
+        max_matches_per_file: options.max_matches_per_file,
+        binary: options.binary,
+        results,
+    };
+    let searched = match source {
+        Source::File(path) => search_path(matcher, searcher, options, path, &mut printer),
+        Source::Buffer(_, bytes) => search_bytes(matcher, searcher, bytes, &mut printer),
+        Source::Member { bytes, .. } => {
+            bytes.and_then(|bytes| search_bytes(matcher, searcher, &bytes, &mut printer))
+        }
+    };
+    // Binary, decoded, decompressed, archived and unsaved files are previewed but never replaced,
+    // since replacements are made in the raw bytes of files on disk. Neither are files with
+    // matches not listed, since apply_replace would replace them unreviewed.
+    if printer.results.more > 0
+        || printer.results.stopped
+        || printer.results.binary.is_some()
+        || printer.results.is_decoded()
+        || printer.results.decompressed.is_some()
+        || printer.results.archive.is_some()
+        || printer.results.unsaved
+    {
+        printer.results.hash = None;
+    }
+    if let Err(e) = searched {
+        Ok((printer.results, Some(RgErr::from(e))))
+    } else {
+        Ok((printer.results, None))
+    }
+}
+
+// Files left out for containing NUL; paths are kept only with `binary_paths`.
+#[derive(Debug, Default)]
+pub struct SkippedBinary {
+    pub count: u64,
+    pub paths: Option<Vec<String>>,
+}
+
+// Yields the results of each file in the order the walker threads finish them, or with
+// `max_matches`, in path order once the walk ends. Files skipped as binary are not yielded, but
+// gathered in `skipped_binary`.
+pub struct RgIter {
+    // Spawned on the first `next`, so that a search waiting for a worker does not walk yet.
+    walk: Option<Box<dyn FnOnce() + Send>>,
+    results: mpsc::Receiver<FileResult>,
+    cancelled: Arc<AtomicBool>,
+    max_matches: Option<usize>,
+    // The results cut at `max_matches`, and whether any match was cut
+    capped: Option<std::vec::IntoIter<FileResult>>,
+    truncated: bool,
+    // Skipped globs, reported along with the results
+    glob_errors: Vec<GlobError>,
+    skipped_binary: SkippedBinary,
+}
+
+// Errors, which have no paths, come last.
+fn sort_by_path(results: &mut [FileResult]) {
+    fn key(result: &FileResult) -> (bool, Option<&str>) {
+        match result {
+            Ok((results, _)) => (false, Some(&results.path)),
+            Err(_) => (true, None),
+        }
+    }
+
+    results.sort_by(|a, b| key(a).cmp(&key(b)));
+}
+
+impl RgIter {
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    // Whether matches were left out at `max_matches`
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+
+    pub fn take_glob_errors(&mut self) -> Vec<GlobError> {
+        std::mem::take(&mut self.glob_errors)
+    }
+
+    // Complete once the iteration ends; paths are sorted.
+    pub fn take_skipped_binary(&mut self) -> SkippedBinary {
+        let mut skipped = std::mem::take(&mut self.skipped_binary);
+        if let Some(paths) = skipped.paths.as_mut() {
+            paths.sort();
+        }
+        skipped
+    }
+
+    // Waits for the whole walk, and yields the results ordered by path.
+    pub fn sorted(&mut self) -> std::vec::IntoIter<FileResult> {
+        let mut results: Vec<_> = self.by_ref().collect();
+        sort_by_path(&mut results);
+        results.into_iter()
+    }
+
+    // Keeps the first `max_matches` matches in path order, so that the same matches are kept
+    // whichever files the walker threads search first. The files after the cut are left out,
+    // while errors are kept.
+    fn cap(&mut self, max_matches: usize) -> std::vec::IntoIter<FileResult> {
+        let mut results: Vec<_> = std::iter::from_fn(|| self.next_walked()).collect();
+        sort_by_path(&mut results);
+
+        let mut left = max_matches;
+        let mut cut = None;
+        for (i, result) in results.iter_mut().enumerate() {
+            let Ok((results, _)) = result else {
+                continue;
+            };
+            if results.matches <= left {
+                left -= results.matches;
+                continue;
+            }
+            if left > 0 {
+                results.truncate_matches(left);
+                cut = Some(i + 1);
+            } else {
+                cut = Some(i);
+            }
+            break;
+        }
+
+        if let Some(cut) = cut {
+            self.truncated = true;
+            let mut i = 0;
+            results.retain(|result| {
+                i += 1;
+                i <= cut || result.is_err()
+            });
+        }
+        results.into_iter()
+    }
+
+    fn next_walked(&mut self) -> Option<FileResult> {
+        if let Some(walk) = self.walk.take() {
+            if self.is_cancelled() {
+                return None;
+            }
+            std::thread::spawn(walk);
+        }
+
+        loop {
+            // Once cancelled, the files already searched are still yielded, but no more are waited
+            // for.
+            let result = if self.is_cancelled() {
+                self.results.try_recv().ok()?
+            } else {
+                self.results.recv().ok()?
+            };
+            match result {
+                Ok((results, None)) if results.skipped => {
+                    self.skipped_binary.count += 1;
+                    if let Some(paths) = self.skipped_binary.paths.as_mut() {
+                        paths.push(results.path);
+                    }
+                }
+                result => return Some(result),
+            }
+        }
+    }
 }
 
-pub fn search_dir<'a, const CONTEXT_LENGTH: usize>(
+impl Iterator for RgIter {
+    type Item = FileResult;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Some(max_matches) = self.max_matches else {
+            return self.next_walked();
+        };
+        if self.capped.is_none() {
+            let capped = self.cap(max_matches);
+            self.capped = Some(capped);
+        }
+        self.capped.as_mut()?.next()
+    }
+}
+
+// Problems found by `validate`, per input field.
+#[derive(Debug, Default)]
+pub struct Diagnostics {
+    pub pattern: Option<PatternError>,
+    pub glob: Vec<GlobError>,
+    pub path: Option<String>,
+}
+
+// Checks the inputs of `search_dir` without searching.
+pub fn validate<'a>(
     dir: &Path,
     pattern: &str,
     glob: impl Iterator<Item = &'a str>,
-) -> Option<impl Iterator<Item = Result<(RgResults<CONTEXT_LENGTH>, Option<RgErr>), RgErr>>> {
-    struct RgIter<W, const CONTEXT_LENGTH: usize> {
-        matcher: RegexMatcher,
-        searcher: Searcher,
-        walker: W,
+    options: &SearchOptions,
+) -> Diagnostics {
+    let pattern = pattern_error(pattern, options);
+    let (_, glob) = build_overrides(dir, glob);
+    let path = std::fs::metadata(dir)
+        .err()
+        .map(|e| format!("{}: {e}", path_to_string(dir)));
+
+    Diagnostics {
+        pattern,
+        glob,
+        path,
     }
+}
 
-    impl<W, const CONTEXT_LENGTH: usize> Iterator for RgIter<W, CONTEXT_LENGTH>
-    where
-        W: Iterator<Item = Result<ignore::DirEntry, ignore::Error>>,
-    {
-        type Item = Result<(RgResults<CONTEXT_LENGTH>, Option<RgErr>), RgErr>;
-
-        fn next(&mut self) -> Option<Self::Item> {
-            let file = self.walker.next()?;
-
-            let res = match file {
-                Ok(file) => {
-                    let mut printer = RgResults::from_path(file.path());
-                    if let Err(e) =
-                        self.searcher
-                            .search_path(&self.matcher, file.path(), &mut printer)
-                    {
-                        Ok((printer, Some(RgErr::from(e))))
-                    } else {
-                        Ok((printer, None))
+#[derive(Debug)]
+pub enum SearchError {
+    Pattern(PatternError),
+    // Invalid globs with `strict_globs`
+    Glob(Vec<GlobError>),
+    // Unknown encodings, or invalid globs of `encodings`
+    Encoding(String),
+}
+
+pub fn search_dir<'a>(
+    dir: &Path,
+    pattern: &str,
+    glob: impl Iterator<Item = &'a str>,
+    options: &SearchOptions,
+    cancelled: Arc<AtomicBool>,
+    threads: usize,
+) -> Result<RgIter, SearchError> {
+    with_matcher!(pattern, options, |matcher| {
+        search_dir_with(matcher, dir, pattern, glob, options, cancelled, threads)
+    })
+}
+
+fn search_dir_with<'a, M>(
+    matcher: M,
+    dir: &Path,
+    pattern: &str,
+    glob: impl Iterator<Item = &'a str>,
+    options: &SearchOptions,
+    cancelled: Arc<AtomicBool>,
+    threads: usize,
+) -> Result<RgIter, SearchError>
+where
+    M: Matcher + Clone + Send + 'static,
+{
+    use ignore::WalkState;
+
+    let (overrides, glob_errors) = build_overrides(dir, glob);
+    if options.strict_globs && !glob_errors.is_empty() {
+        return Err(SearchError::Glob(glob_errors));
+    }
+    let encodings = Arc::new(Encodings::new(dir, options)?);
+    let walker = build_walker(dir, overrides, threads);
+    let literals = literal_patterns(pattern, options);
+
+    let (tx, rx) = mpsc::sync_channel(RESULTS_BUFFER);
+    let flag = cancelled.clone();
+    let max_matches = options.max_matches;
+    let skipped_binary = SkippedBinary {
+        count: 0,
+        paths: options.binary_paths.then(Vec::new),
+    };
+    let options = options.clone();
+
+    let walk = move || {
+        walker.run(|| {
+            // One matcher and searcher per walker thread.
+            let matcher = matcher.clone();
+            let literals = literals.clone();
+            let mut searchers = encodings.searchers(&options);
+            // Buffers hold UTF-8 whatever the encodings of their files.
+            let mut buffer_searcher = FileSearcher {
+                searcher: build_searcher(&options, None),
+                encoding: None,
+            };
+            let encodings = encodings.clone();
+            let options = options.clone();
+            let cancelled = flag.clone();
+            let tx = tx.clone();
+
+            Box::new(move |entry| {
+                if cancelled.load(Ordering::Relaxed) {
+                    return WalkState::Quit;
+                }
+                let Ok(entry) = entry else {
+                    return WalkState::Continue;
+                };
+                if !is_file(&entry) {
+                    return WalkState::Continue;
+                }
+
+                let kind = options
+                    .archives
+                    .then(|| archive::Kind::from_path(entry.path()))
+                    .flatten();
+                if let Some(kind) = kind {
+                    let mut state = WalkState::Continue;
+                    let listed = archive::for_each_member(kind, entry.path(), |name, bytes| {
+                        // Members are matched against `encodings` by their paths in the archive.
+                        let index = encodings.index(&archive::member_path(entry.path(), &name));
+                        let source = Source::Member {
+                            archive: entry.path(),
+                            name,
+                            bytes,
+                        };
+                        let result = search_file(
+                            &matcher,
+                            &literals,
+                            &mut searchers[index],
+                            &options,
+                            &cancelled,
+                            source,
+                        );
+                        if tx.send(result).is_err() {
+                            state = WalkState::Quit;
+                        }
+                        matches!(state, WalkState::Continue) && !cancelled.load(Ordering::Relaxed)
+                    });
+                    if let Err(e) = listed {
+                        let results = RgResults::from_path(entry.path(), &options);
+                        if tx.send(Ok((results, Some(RgErr::from(e))))).is_err() {
+                            return WalkState::Quit;
+                        }
+                    }
+                    return state;
+                }
+
+                let (searcher, source) = match options.buffers.get(&entry) {
+                    Some(bytes) => (&mut buffer_searcher, Source::Buffer(entry.path(), bytes)),
+                    None => (
+                        &mut searchers[encodings.index(entry.path())],
+                        Source::File(entry.path()),
+                    ),
+                };
+                let result =
+                    search_file(&matcher, &literals, searcher, &options, &cancelled, source);
+                if tx.send(result).is_err() {
+                    WalkState::Quit
+                } else {
+                    WalkState::Continue
+                }
+            })
+        });
+    };
+
+    Ok(RgIter {
+        walk: Some(Box::new(walk)),
+        results: rx,
+        cancelled,
+        max_matches,
+        capped: None,
+        truncated: false,
+        glob_errors,
+        skipped_binary,
+    })
+}
+
+// Replaces the matches in each of `files`, given with their content hashes when searched.
+pub fn replace_files(
+    pattern: &str,
+    options: &SearchOptions,
+    files: &[(PathBuf, String)],
+) -> Result<Vec<FileEdit>, SearchError> {
+    let replacement = options.replacement().unwrap_or(Replacement {
+        template: b"",
+        preserve_case: options.preserve_case,
+    });
+
+    with_matcher!(pattern, options, |matcher| {
+        Ok(replace_files_with(
+            &matcher,
+            replacement,
+            &options.buffers,
+            files,
+        ))
+    })
+}
+
+fn replace_files_with<M: Matcher>(
+    matcher: &M,
+    replacement: Replacement<'_>,
+    buffers: &Buffers,
+    files: &[(PathBuf, String)],
+) -> Vec<FileEdit> {
+    files
+        .iter()
+        .map(|(path, hash)| {
+            if buffers.contains(path) {
+                return edit::unsaved_file(path);
+            }
+            edit::edit_file(path, hash, |bytes| {
+                let (replaced, ranges) = replace_bytes(matcher, replacement, bytes);
+                (replaced, ranges.len())
+            })
+        })
+        .collect()
+}
+
+// A unified diff of the replacements in a file; `path` is relative to the base of `diff_dir`.
+pub struct FileDiff {
+    pub path: String,
+    pub diff: Result<String, RgErr>,
+}
+
+pub struct DiffResults {
+    pub diffs: Vec<FileDiff>,
+    pub glob_errors: Vec<GlobError>,
+    // Whether the walk stopped before the end; `diffs` are those of the files already walked.
+    pub cancelled: bool,
+}
+
+// How `diff_dir` writes the diffs
+#[derive(Debug, Clone, Copy)]
+pub struct DiffFormat<'a> {
+    // Paths in the diffs are relative to it, so that they can be applied there with `git apply`.
+    pub base: &'a Path,
+    // Unchanged lines around each change
+    pub context: usize,
+}
+
+// Diffs the files under `dir` against their contents with the matches replaced by
+// `options.replacement`. Unchanged and binary files are left out.
+pub fn diff_dir<'a>(
+    dir: &Path,
+    pattern: &str,
+    glob: impl Iterator<Item = &'a str>,
+    options: &SearchOptions,
+    format: DiffFormat<'_>,
+    cancelled: &AtomicBool,
+    threads: usize,
+) -> Result<DiffResults, SearchError> {
+    with_matcher!(pattern, options, |matcher| {
+        diff_dir_with(matcher, dir, glob, options, format, cancelled, threads)
+    })
+}
+
+fn diff_dir_with<'a, M>(
+    matcher: M,
+    dir: &Path,
+    glob: impl Iterator<Item = &'a str>,
+    options: &SearchOptions,
+    format: DiffFormat<'_>,
+    cancelled: &AtomicBool,
+    threads: usize,
+) -> Result<DiffResults, SearchError>
+where
+    M: Matcher + Clone + Send + 'static,
+{
+    use ignore::WalkState;
+
+    let (overrides, glob_errors) = build_overrides(dir, glob);
+    if options.strict_globs && !glob_errors.is_empty() {
+        return Err(SearchError::Glob(glob_errors));
+    }
+    let walker = build_walker(dir, overrides, threads);
+    let replacement = options.replacement().unwrap_or(Replacement {
+        template: b"",
+        preserve_case: options.preserve_case,
+    });
+
+    let (tx, rx) = mpsc::channel();
+    walker.run(|| {
+        let matcher = matcher.clone();
+        let tx = tx.clone();
+
+        Box::new(move |entry| {
+            if cancelled.load(Ordering::Relaxed) {
+                return WalkState::Quit;
+            }
+            let Ok(entry) = entry else {
+                return WalkState::Continue;
+            };
+            if !is_file(&entry) {
+                return WalkState::Continue;
+            }
+
+            let path = entry.path();
+            let label = path_to_string(path.strip_prefix(format.base).unwrap_or(path));
+            // A diff of the unsaved lines would not apply to the file on disk.
+            if options.buffers.get(&entry).is_some() {
+                let diff = Err(RgErr {
+                    msg: edit::UNSAVED.to_string(),
+                });
+                tx.send(FileDiff { path: label, diff }).ok();
+                return WalkState::Continue;
+            }
+            let diff = match std::fs::read(path) {
+                Ok(bytes) if bytes.contains(&0) => return WalkState::Continue,
+                Ok(bytes) => {
+                    let (replaced, changes) = replace_bytes(&matcher, replacement, &bytes);
+                    match diff::unified(&label, &bytes, &replaced, &changes, format.context) {
+                        Some(diff) => Ok(diff),
+                        None => return WalkState::Continue,
                     }
                 }
                 Err(e) => Err(RgErr::from(e)),
             };
+            tx.send(FileDiff { path: label, diff }).ok();
+            WalkState::Continue
+        })
+    });
+    drop(tx);
 
-            Some(res)
-        }
+    let mut diffs: Vec<_> = rx.into_iter().collect();
+    diffs.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(DiffResults {
+        diffs,
+        glob_errors,
+        cancelled: cancelled.load(Ordering::Relaxed),
+    })
+}
+
+// Renames planned by `rename_paths`, relative to the searched directory
+#[derive(Debug)]
+pub struct RenamePlan {
+    pub renames: Vec<Rename>,
+    pub glob_errors: Vec<GlobError>,
+}
+
+// Replaces the matches in the paths of files under `dir`, relative to `dir`. Files whose paths do
+// not change are left out.
+pub fn rename_paths<'a>(
+    dir: &Path,
+    pattern: &str,
+    replacement: &str,
+    glob: impl Iterator<Item = &'a str>,
+    options: &SearchOptions,
+    threads: usize,
+) -> Result<RenamePlan, SearchError> {
+    with_matcher!(pattern, options, |matcher| {
+        rename_paths_with(matcher, dir, replacement, glob, options, threads)
+    })
+}
+
+fn rename_paths_with<'a, M>(
+    matcher: M,
+    dir: &Path,
+    replacement: &str,
+    glob: impl Iterator<Item = &'a str>,
+    options: &SearchOptions,
+    threads: usize,
+) -> Result<RenamePlan, SearchError>
+where
+    M: Matcher + Clone + Send + 'static,
+{
+    use ignore::WalkState;
+
+    let (overrides, glob_errors) = build_overrides(dir, glob);
+    if options.strict_globs && !glob_errors.is_empty() {
+        return Err(SearchError::Glob(glob_errors));
     }
+    let walker = build_walker(dir, overrides, threads);
+    let replacement = Replacement {
+        template: replacement.as_bytes(),
+        preserve_case: options.preserve_case,
+    };
+
+    let (tx, rx) = mpsc::channel();
+    walker.run(|| {
+        let matcher = matcher.clone();
+        let tx = tx.clone();
+
+        Box::new(move |entry| {
+            let Ok(entry) = entry else {
+                return WalkState::Continue;
+            };
+            if !is_file(&entry) {
+                return WalkState::Continue;
+            }
+            // Paths which are not UTF-8 cannot be sent to Lua anyway.
+            let Some(from) = entry.path().strip_prefix(dir).ok().and_then(Path::to_str) else {
+                return WalkState::Continue;
+            };
+
+            let (to, ranges) = replace_bytes(&matcher, replacement, from.as_bytes());
+            let to = String::from_utf8_lossy(&to);
+            if !ranges.is_empty() && to != from {
+                tx.send((from.to_string(), to.into_owned())).ok();
+            }
+            WalkState::Continue
+        })
+    });
+    drop(tx);
 
-    let matcher = build_matcher(pattern).ok()?;
-    let searcher = build_searcher::<CONTEXT_LENGTH>();
-    let walker = build_walker(dir, glob);
+    let mut pairs: Vec<_> = rx.into_iter().collect();
+    pairs.sort();
 
-    Some(RgIter {
-        matcher,
-        searcher,
-        walker,
+    Ok(RenamePlan {
+        renames: edit::plan_renames(dir, pairs),
+        glob_errors,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::{self, TestDir};
+
+    // The files searched under `dir`, in path order
+    fn search(dir: &TestDir, pattern: &str, options: &SearchOptions) -> Vec<RgResults> {
+        let glob = std::iter::empty();
+        let mut results = search_dir(dir.path(), pattern, glob, options, Default::default(), 1)
+            .unwrap_or_else(|e| panic!("{e:?}"));
+        results
+            .sorted()
+            .map(|result| result.ok().unwrap().0)
+            .collect()
+    }
+
+    // The matched lines of each file, by their line numbers
+    fn matched_lines(results: &[RgResults]) -> Vec<(u64, Vec<String>)> {
+        results
+            .iter()
+            .flat_map(|results| &results.inner)
+            .filter_map(|result| Some((result.line_idx?, result.matched.clone()?)))
+            .collect()
+    }
+
+    #[test]
+    fn default_search_stays_within_lines() {
+        let dir = TestDir::new("lines");
+        dir.write("a.txt", "foo\nbar\n[a\nb]\n");
+        let options = SearchOptions::default();
+
+        assert!(matched_lines(&search(&dir, r"foo\s+bar", &options)).is_empty());
+        assert!(matched_lines(&search(&dir, r"\[[^\]]*\]", &options)).is_empty());
+        assert!(matches!(
+            search_dir(
+                dir.path(),
+                r"foo\nbar",
+                std::iter::empty(),
+                &options,
+                Default::default(),
+                1
+            ),
+            Err(SearchError::Pattern(_))
+        ));
+
+        let options = SearchOptions {
+            multi_line: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            matched_lines(&search(&dir, r"foo\s+bar", &options)),
+            vec![(1, vec!["foo".to_string(), "bar".to_string()])]
+        );
+    }
+
+    #[test]
+    fn convert_keeps_line_numbers_after_nul() {
+        let dir = TestDir::new("convert");
+        dir.write("a.bin", "one\nnul\0 here x\n x\n");
+        let options = SearchOptions {
+            binary: BinaryMode::Convert,
+            ..Default::default()
+        };
+
+        let results = search(&dir, "x", &options);
+        assert_eq!(results[0].binary(), Some(7));
+        assert_eq!(
+            matched_lines(&results),
+            vec![
+                (2, vec!["nul. here x".to_string()]),
+                (3, vec![" x".to_string()]),
+            ]
+        );
+        let span = &results[0].inner[0].spans[0];
+        assert_eq!((span.start, span.end, span.start_col), (10, 11, 10));
+    }
+
+    #[test]
+    fn caps_cut_in_path_order() {
+        let dir = TestDir::new("caps");
+        for name in ["a", "b", "c", "d"] {
+            dir.write(&format!("{name}.txt"), "x1\nx2\nx3\n");
+        }
+
+        let options = SearchOptions::default();
+        let results = search(&dir, "x", &options);
+        assert_eq!(results.len(), 4);
+        assert!(results.iter().all(|results| results.matches == 3));
+
+        let options = SearchOptions {
+            max_matches_per_file: Some(2),
+            ..Default::default()
+        };
+        let results = search(&dir, "x", &options);
+        assert!(
+            results
+                .iter()
+                .all(|results| results.matches == 2 && results.more == 1)
+        );
+
+        let options = SearchOptions {
+            max_matches: Some(4),
+            replacement: Some("y".to_string()),
+            ..Default::default()
+        };
+        let mut iter = search_dir(
+            dir.path(),
+            "x",
+            std::iter::empty(),
+            &options,
+            Default::default(),
+            0,
+        )
+        .unwrap();
+        let results: Vec<_> = iter.by_ref().map(|result| result.ok().unwrap().0).collect();
+        assert!(iter.is_truncated());
+        let paths: Vec<_> = results.iter().map(|results| results.path()).collect();
+        assert_eq!(paths.len(), 2);
+        assert!(paths[0].ends_with("a.txt") && paths[1].ends_with("b.txt"));
+        assert_eq!((results[0].matches, results[0].more), (3, 0));
+        assert_eq!((results[1].matches, results[1].more), (1, 2));
+        assert!(results[0].hash().is_some());
+        assert!(results[1].hash().is_none());
+        assert_eq!(
+            matched_lines(&results[1..]),
+            vec![(1, vec!["x1".to_string()])]
+        );
+    }
+
+    // (start, end, start_char, end_char, start_col, end_col) of each span, by line
+    fn offsets(spans: &[Span]) -> Vec<(usize, [usize; 6])> {
+        spans
+            .iter()
+            .map(|span| {
+                let offsets = [
+                    span.start,
+                    span.end,
+                    span.start_char,
+                    span.end_char,
+                    span.start_col,
+                    span.end_col,
+                ];
+                (span.line, offsets)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn spans_count_chars_and_columns() {
+        let options = SearchOptions::default();
+        let matcher = build_matcher("日本|x", &options).unwrap();
+
+        let spans = match_spans(&matcher, &[], "añ 日本 x\n".as_bytes());
+        assert_eq!(
+            offsets(&spans),
+            vec![(0, [4, 10, 3, 5, 3, 7]), (0, [11, 12, 6, 7, 8, 9])]
+        );
+    }
+
+    #[test]
+    fn spans_split_per_line() {
+        let bytes = "é1\nab\r\nc\n".as_bytes();
+
+        // The \r\n of the second line is left out of its span, and a range of only the terminator
+        // is dropped.
+        let spans: Vec<_> = line_spans(bytes, &[2..10, 6..8, 9..9])
+            .into_iter()
+            .map(|(i, span)| (i, span.line, span.start, span.end, span.end_col))
+            .collect();
+        assert_eq!(
+            spans,
+            vec![
+                (0, 0, 2, 3, 2),
+                (0, 1, 0, 2, 2),
+                (0, 2, 0, 1, 1),
+                (2, 2, 1, 1, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn spans_name_their_literals() {
+        let options = SearchOptions {
+            mode: PatternMode::Literal,
+            case: CaseMode::Insensitive,
+            literals: vec!["Bar".to_string()],
+            ..Default::default()
+        };
+        let literals = literal_patterns("foo", &options);
+        let matcher = build_matcher("foo", &options).unwrap();
+
+        let spans = match_spans(&matcher, &literals, b"bar FOO Bar");
+        let literals: Vec<_> = spans.iter().map(|span| span.literal).collect();
+        assert_eq!(literals, vec![Some(1), Some(0), Some(1)]);
+    }
+
+    #[test]
+    fn overlapping_context_is_shared() {
+        let dir = TestDir::new("context");
+        dir.write("a.txt", "1\n2\nx3\n4\nx5\n6\n7\n8\n9\nx10\n");
+        let options = SearchOptions {
+            before_context: 2,
+            after_context: 2,
+            ..Default::default()
+        };
+
+        // Before-context is nearest first.
+        let results = search(&dir, "x", &options);
+        let context: Vec<_> = results[0]
+            .inner
+            .iter()
+            .map(|result| (result.line_idx, &result.before, &result.after))
+            .collect();
+        let some = |lines: &[&str]| -> Vec<Option<String>> {
+            lines.iter().map(|line| Some(line.to_string())).collect()
+        };
+        assert_eq!(
+            context,
+            vec![
+                (Some(3), &some(&["2", "1"]), &some(&["4", "x5"])),
+                (Some(5), &some(&["4", "x3"]), &some(&["6", "7"])),
+                (Some(10), &some(&["9", "8"]), &vec![None, None]),
+            ]
+        );
+    }
+
+    // A match on the first line, and another after NUL beyond the first chunk read
+    fn write_binary(dir: &TestDir) -> usize {
+        let mut bytes = b"x first\n".to_vec();
+        bytes.extend(b"line\n".repeat(100_000));
+        let nul = bytes.len();
+        bytes.extend(b"\0\nx last\n");
+        dir.write("a.bin", bytes);
+        nul
+    }
+
+    #[test]
+    fn skip_leaves_binary_files_out() {
+        let dir = TestDir::new("skip");
+        write_binary(&dir);
+        dir.write("b.txt", "x text\n");
+        let options = SearchOptions {
+            binary_paths: true,
+            ..Default::default()
+        };
+
+        let mut iter = search_dir(
+            dir.path(),
+            "x",
+            std::iter::empty(),
+            &options,
+            Default::default(),
+            1,
+        )
+        .unwrap();
+        let results: Vec<_> = iter.by_ref().map(|result| result.ok().unwrap().0).collect();
+        assert_eq!(results.len(), 1);
+        assert!(results[0].path().ends_with("b.txt"));
+
+        let skipped = iter.take_skipped_binary();
+        assert_eq!(skipped.count, 1);
+        assert!(skipped.paths.unwrap()[0].ends_with("a.bin"));
+    }
+
+    #[test]
+    fn quit_keeps_matches_before_nul() {
+        let dir = TestDir::new("quit");
+        let nul = write_binary(&dir);
+        let options = SearchOptions {
+            binary: BinaryMode::Quit,
+            ..Default::default()
+        };
+
+        let results = search(&dir, "x", &options);
+        assert_eq!(results[0].binary(), Some(nul as u64));
+        assert_eq!(
+            matched_lines(&results),
+            vec![(1, vec!["x first".to_string()])]
+        );
+    }
+
+    #[test]
+    fn files_are_decoded() {
+        let dir = TestDir::new("encoding");
+        let utf16: Vec<u8> = [0xfeff]
+            .into_iter()
+            .chain("héllo\n".encode_utf16())
+            .flat_map(u16::to_le_bytes)
+            .collect();
+        dir.write("a.txt", utf16);
+        // 日本 in Shift_JIS
+        dir.write("b.sjis", b"\x93\xfa\x96\x7b\n");
+        dir.write("c.txt", "日本\n");
+        let options = SearchOptions {
+            encodings: vec![("*.sjis".to_string(), "shift_jis".to_string())],
+            ..Default::default()
+        };
+
+        let results = search(&dir, "héllo|日本", &options);
+        let encodings: Vec<_> = results.iter().map(RgResults::encoding).collect();
+        assert_eq!(encodings, vec![Some("UTF-16LE"), Some("Shift_JIS"), None]);
+        assert_eq!(
+            matched_lines(&results),
+            vec![
+                (1, vec!["héllo".to_string()]),
+                (1, vec!["日本".to_string()]),
+                (1, vec!["日本".to_string()]),
+            ]
+        );
+        assert!(results.iter().all(|results| results.hash().is_none()));
+    }
+
+    #[test]
+    fn compressed_and_archived_files_are_searched() {
+        let dir = TestDir::new("contents");
+        dir.write("a.txt.gz", test_dir::gzip(b"x in gzip\n"));
+        dir.write("b.zip", test_dir::zip(&[("src/lib.rs", "x in zip\n")]));
+
+        // Searched as is by default, where neither matches.
+        assert!(search(&dir, "x in", &SearchOptions::default()).is_empty());
+
+        let options = SearchOptions {
+            decompress: true,
+            archives: true,
+            ..Default::default()
+        };
+        let results = search(&dir, "x in", &options);
+        assert_eq!(results[0].decompressed(), Some("gzip"));
+        let (archive, member) = results[1].archive().unwrap();
+        assert!(archive.ends_with("b.zip") && member == "src/lib.rs");
+        assert!(results[1].path().ends_with("b.zip!/src/lib.rs"));
+        assert_eq!(
+            matched_lines(&results),
+            vec![
+                (1, vec!["x in gzip".to_string()]),
+                (1, vec!["x in zip".to_string()]),
+            ]
+        );
+    }
+}
@@ -1,4 +1,11 @@
-use crate::rg::{RgErr, RgResult, RgResults};
+use crate::rg::{
+    BinaryMode, Buffers, CaseMode, Diagnostics, DiffResults, Engine, FileResult, GlobError, Greed,
+    PatternError, PatternMode, RenamePlan, RgErr, RgResult, RgResults, SearchError, SearchOptions,
+    SkippedBinary, Span, encoding_for_label,
+};
+
+use crate::diff;
+use crate::edit::{EditStatus, FileEdit, LineEdit, LineIssue, Rename, RenameStatus};
 
 use nvim_router::nvim_rs::Value;
 
@@ -10,11 +17,299 @@ fn err_value(e: RgErr, path: Option<&str>) -> Value {
     Value::Map(inner)
 }
 
-fn result_value<const CONTEXT_LENGTH: usize>(
-    result: RgResult<CONTEXT_LENGTH>,
-    path: &str,
-) -> Value {
-    let mut inner = vec![(Value::from("path"), Value::from(path))];
+// { error, kind, span = { start, end } }, where span is the byte range in the pattern.
+pub fn pattern_err_value(e: PatternError) -> Value {
+    let mut inner = vec![
+        (Value::from("error"), Value::from(e.msg)),
+        (Value::from("kind"), Value::from(e.kind)),
+    ];
+    if let Some((start, end)) = e.span {
+        inner.push((
+            Value::from("span"),
+            Value::Map(vec![
+                (Value::from("start"), Value::from(start)),
+                (Value::from("end"), Value::from(end)),
+            ]),
+        ));
+    }
+    Value::Map(inner)
+}
+
+fn glob_err_value(e: GlobError) -> Value {
+    Value::Map(vec![
+        (Value::from("error"), Value::from(e.msg)),
+        (Value::from("index"), Value::from(e.index)),
+        (Value::from("glob"), Value::from(e.glob)),
+    ])
+}
+
+fn glob_errs_value(errors: Vec<GlobError>) -> Value {
+    Value::Array(errors.into_iter().map(glob_err_value).collect())
+}
+
+// Pattern errors carry `kind`, and glob errors carry `glob_errors`.
+pub fn search_err_value(e: SearchError) -> Value {
+    match e {
+        SearchError::Pattern(e) => pattern_err_value(e),
+        SearchError::Encoding(msg) => msg_value(msg),
+        SearchError::Glob(errors) => {
+            let globs: Vec<_> = errors.iter().map(|e| e.glob.as_str()).collect();
+            Value::Map(vec![
+                (
+                    Value::from("error"),
+                    Value::from(format!("Invalid globs: {}", globs.join(" "))),
+                ),
+                (Value::from("glob_errors"), glob_errs_value(errors)),
+            ])
+        }
+    }
+}
+
+// { pattern = pattern error, glob = [glob errors], path = { error } }; valid fields are omitted.
+pub fn diagnostics_value(diagnostics: Diagnostics) -> Value {
+    let mut inner = Vec::new();
+    if let Some(e) = diagnostics.pattern {
+        inner.push((Value::from("pattern"), pattern_err_value(e)));
+    }
+    if !diagnostics.glob.is_empty() {
+        inner.push((Value::from("glob"), glob_errs_value(diagnostics.glob)));
+    }
+    if let Some(msg) = diagnostics.path {
+        inner.push((Value::from("path"), msg_value(msg)));
+    }
+    Value::Map(inner)
+}
+
+pub fn msg_value(msg: impl Into<String>) -> Value {
+    Value::Map(vec![(Value::from("error"), Value::from(msg.into()))])
+}
+
+// Options are sent as a list of `{ key, value }` pairs.
+pub fn option_pairs(items: &[Value]) -> Result<Vec<(&str, &Value)>, Value> {
+    items
+        .iter()
+        .map(|item| match item.as_array().map(Vec::as_slice) {
+            Some([key, value]) => key
+                .as_str()
+                .map(|key| (key, value))
+                .ok_or_else(|| msg_value(format!("Option key must be a string: {key}"))),
+            _ => Err(msg_value(format!(
+                "Option must be a {{ key, value }} pair: {item}"
+            ))),
+        })
+        .collect()
+}
+
+fn bool_option(key: &str, value: &Value) -> Result<bool, Value> {
+    value
+        .as_bool()
+        .ok_or_else(|| msg_value(format!("{key} must be a boolean: {value}")))
+}
+
+fn usize_option(key: &str, value: &Value) -> Result<usize, Value> {
+    value
+        .as_u64()
+        .map(|value| value as usize)
+        .ok_or_else(|| msg_value(format!("{key} must be a non-negative integer: {value}")))
+}
+
+// A cap, where 0 is no cap
+fn limit_option(key: &str, value: &Value) -> Result<Option<usize>, Value> {
+    usize_option(key, value).map(|limit| (limit > 0).then_some(limit))
+}
+
+fn str_option<'a>(key: &str, value: &'a Value) -> Result<&'a str, Value> {
+    value
+        .as_str()
+        .ok_or_else(|| msg_value(format!("{key} must be a string: {value}")))
+}
+
+// Encodings are sent as a list of `{ glob, encoding }` pairs.
+fn encoding_pairs(value: &Value) -> Result<Vec<(String, String)>, Value> {
+    let Some(items) = value.as_array() else {
+        return Err(msg_value(format!("encodings must be an array: {value}")));
+    };
+
+    items
+        .iter()
+        .map(|item| match item.as_array().map(Vec::as_slice) {
+            Some([glob, label]) => match (glob.as_str(), label.as_str()) {
+                (Some(glob), Some(label)) if encoding_for_label(label).is_some() => {
+                    Ok((glob.to_string(), label.to_string()))
+                }
+                (Some(_), Some(label)) => Err(msg_value(format!("Unknown encoding: {label}"))),
+                _ => Err(msg_value(format!(
+                    "Encoding glob and label must be strings: {item}"
+                ))),
+            },
+            _ => Err(msg_value(format!(
+                "Encoding must be a {{ glob, encoding }} pair: {item}"
+            ))),
+        })
+        .collect()
+}
+
+pub fn search_options(items: &[Value]) -> Result<SearchOptions, Value> {
+    let mut options = SearchOptions::default();
+
+    for (key, value) in option_pairs(items)? {
+        match key {
+            "case" => {
+                options.case = match str_option(key, value)? {
+                    "smart" => CaseMode::Smart,
+                    "sensitive" => CaseMode::Sensitive,
+                    "insensitive" => CaseMode::Insensitive,
+                    case => {
+                        return Err(msg_value(format!(
+                            "case must be one of smart, sensitive or insensitive: {case}"
+                        )));
+                    }
+                };
+            }
+            "greed" => {
+                options.greed = match str_option(key, value)? {
+                    "greedy" => Greed::Greedy,
+                    "lazy" => Greed::Lazy,
+                    greed => {
+                        return Err(msg_value(format!(
+                            "greed must be one of greedy or lazy: {greed}"
+                        )));
+                    }
+                };
+            }
+            "engine" => {
+                options.engine = match str_option(key, value)? {
+                    "rust" => Engine::Rust,
+                    "pcre2" => Engine::Pcre2,
+                    engine => {
+                        return Err(msg_value(format!(
+                            "engine must be one of rust or pcre2: {engine}"
+                        )));
+                    }
+                };
+            }
+            "mode" => {
+                options.mode = match str_option(key, value)? {
+                    "regex" => PatternMode::Regex,
+                    "literal" => PatternMode::Literal,
+                    mode => {
+                        return Err(msg_value(format!(
+                            "mode must be one of regex or literal: {mode}"
+                        )));
+                    }
+                };
+            }
+            "binary" => {
+                options.binary = match str_option(key, value)? {
+                    "skip" => BinaryMode::Skip,
+                    "quit" => BinaryMode::Quit,
+                    "convert" => BinaryMode::Convert,
+                    binary => {
+                        return Err(msg_value(format!(
+                            "binary must be one of skip, quit or convert: {binary}"
+                        )));
+                    }
+                };
+            }
+            "encoding" => {
+                let label = str_option(key, value)?;
+                if encoding_for_label(label).is_none() {
+                    return Err(msg_value(format!("Unknown encoding: {label}")));
+                }
+                options.encoding = Some(label.to_string());
+            }
+            "encodings" => options.encodings = encoding_pairs(value)?,
+            "literals" => {
+                let Some(literals) = value.as_array() else {
+                    return Err(msg_value(format!("literals must be an array: {value}")));
+                };
+                options.literals = literals
+                    .iter()
+                    .map(|literal| str_option(key, literal).map(str::to_string))
+                    .collect::<Result<_, _>>()?;
+            }
+            "word" => options.word = bool_option(key, value)?,
+            // An alias of the literal mode; false leaves `mode` as it is.
+            "fixed_strings" => {
+                if bool_option(key, value)? {
+                    options.mode = PatternMode::Literal;
+                }
+            }
+            "invert" => options.invert = bool_option(key, value)?,
+            "multi_line" => options.multi_line = bool_option(key, value)?,
+            "dot_matches_new_line" => options.dot_matches_new_line = bool_option(key, value)?,
+            "crlf" => options.crlf = bool_option(key, value)?,
+            "strict_globs" => options.strict_globs = bool_option(key, value)?,
+            "preserve_case" => options.preserve_case = bool_option(key, value)?,
+            "before_context" => options.before_context = usize_option(key, value)?,
+            "after_context" => options.after_context = usize_option(key, value)?,
+            "max_matches_per_file" => options.max_matches_per_file = limit_option(key, value)?,
+            "max_matches" => options.max_matches = limit_option(key, value)?,
+            "binary_paths" => options.binary_paths = bool_option(key, value)?,
+            "decompress" => options.decompress = bool_option(key, value)?,
+            "archives" => options.archives = bool_option(key, value)?,
+            _ => return Err(msg_value(format!("Unknown option: {key}"))),
+        }
+    }
+
+    Ok(options)
+}
+
+// `literal` is the 0-based index in [pattern, literals...], only in the literal mode.
+fn span_value(span: Span) -> Value {
+    let mut inner = vec![
+        (Value::from("line"), Value::from(span.line)),
+        (Value::from("start"), Value::from(span.start)),
+        (Value::from("end"), Value::from(span.end)),
+        (Value::from("start_char"), Value::from(span.start_char)),
+        (Value::from("end_char"), Value::from(span.end_char)),
+        (Value::from("start_col"), Value::from(span.start_col)),
+        (Value::from("end_col"), Value::from(span.end_col)),
+    ];
+    if let Some(literal) = span.literal {
+        inner.push((Value::from("literal"), Value::from(literal)));
+    }
+    Value::Map(inner)
+}
+
+// { path, hash, binary, encoding, decompressed, archive, member, unsaved } shared by the results
+// of a file, where binary is the offset of the first NUL in a file searched despite it, encoding is
+// the name of the encoding decoded from, decompressed is one of gzip, xz, zstd or bzip2, archive
+// and member are the archive path and the member name of a path like vendor.zip!/src/lib.rs, and
+// unsaved is true if searched in a modified buffer.
+fn file_fields(results: &RgResults) -> Vec<(Value, Value)> {
+    let mut inner = vec![(Value::from("path"), Value::from(results.path()))];
+
+    if let Some(hash) = results.hash() {
+        inner.push((Value::from("hash"), Value::from(hash)));
+    }
+
+    if let Some(binary) = results.binary() {
+        inner.push((Value::from("binary"), Value::from(binary)));
+    }
+
+    if let Some(encoding) = results.encoding() {
+        inner.push((Value::from("encoding"), Value::from(encoding)));
+    }
+
+    if let Some(format) = results.decompressed() {
+        inner.push((Value::from("decompressed"), Value::from(format)));
+    }
+
+    if let Some((archive, member)) = results.archive() {
+        inner.push((Value::from("archive"), Value::from(archive)));
+        inner.push((Value::from("member"), Value::from(member)));
+    }
+
+    if results.is_unsaved() {
+        inner.push((Value::from("unsaved"), Value::from(true)));
+    }
+
+    inner
+}
+
+fn result_value(result: RgResult, file: &[(Value, Value)]) -> Value {
+    let mut inner = file.to_vec();
 
     if let Some(value) = result.line_idx {
         inner.push((Value::from("line_idx"), Value::from(value)));
@@ -25,6 +320,7 @@ fn result_value<const CONTEXT_LENGTH: usize>(
         Value::Array(
             result
                 .before
+                .into_iter()
                 .map(|line| {
                     if let Some(line) = line {
                         Value::from(line)
@@ -32,7 +328,7 @@ fn result_value<const CONTEXT_LENGTH: usize>(
                         Value::Nil
                     }
                 })
-                .to_vec(),
+                .collect(),
         ),
     ));
 
@@ -41,6 +337,7 @@ fn result_value<const CONTEXT_LENGTH: usize>(
         Value::Array(
             result
                 .after
+                .into_iter()
                 .map(|line| {
                     if let Some(line) = line {
                         Value::from(line)
@@ -48,7 +345,7 @@ fn result_value<const CONTEXT_LENGTH: usize>(
                         Value::Nil
                     }
                 })
-                .to_vec(),
+                .collect(),
         ),
     ));
 
@@ -57,33 +354,393 @@ fn result_value<const CONTEXT_LENGTH: usize>(
             Value::from("matched"),
             Value::Array(value.into_iter().map(Value::from).collect()),
         ));
+        inner.push((
+            Value::from("spans"),
+            Value::Array(result.spans.into_iter().map(span_value).collect()),
+        ));
+    }
+
+    if let Some(replaced) = result.replaced {
+        inner.push((
+            Value::from("replaced"),
+            Value::Map(vec![
+                (
+                    Value::from("lines"),
+                    Value::Array(replaced.lines.into_iter().map(Value::from).collect()),
+                ),
+                (
+                    Value::from("spans"),
+                    Value::Array(replaced.spans.into_iter().map(span_value).collect()),
+                ),
+            ]),
+        ));
     }
 
     Value::Map(inner)
 }
 
-pub fn to_values<const CONTEXT_LENGTH: usize>(
-    search_results: impl Iterator<Item = Result<(RgResults<CONTEXT_LENGTH>, Option<RgErr>), RgErr>>,
+#[derive(Default)]
+pub struct Totals {
+    files: u64,
+    matched_files: u64,
+    matches: u64,
+    // Matches not listed, over max_matches_per_file or max_matches
+    more: u64,
+    errors: u64,
+}
+
+fn push_skipped_binary(inner: &mut Vec<(Value, Value)>, skipped: SkippedBinary) {
+    inner.push((Value::from("binary"), Value::from(skipped.count)));
+    if let Some(paths) = skipped.paths {
+        inner.push((
+            Value::from("binary_paths"),
+            Value::Array(paths.into_iter().map(Value::from).collect()),
+        ));
+    }
+}
+
+impl Totals {
+    // truncated: whether matches were cut at max_matches
+    // binary: files skipped as binary, as `binary` and `binary_paths`
+    pub fn into_value(
+        self,
+        cancelled: bool,
+        truncated: bool,
+        binary: SkippedBinary,
+        glob_errors: Vec<GlobError>,
+    ) -> Value {
+        let mut inner = vec![
+            (Value::from("files"), Value::from(self.files)),
+            (
+                Value::from("matched_files"),
+                Value::from(self.matched_files),
+            ),
+            (Value::from("matches"), Value::from(self.matches)),
+            (Value::from("more"), Value::from(self.more)),
+            (Value::from("errors"), Value::from(self.errors)),
+            (Value::from("cancelled"), Value::from(cancelled)),
+            (Value::from("truncated"), Value::from(truncated)),
+            (Value::from("glob_errors"), glob_errs_value(glob_errors)),
+        ];
+        push_skipped_binary(&mut inner, binary);
+        Value::Map(inner)
+    }
+}
+
+// Converts the results of a single file, pushing errors separately so that callers can gather
+// them at the end of the list.
+fn file_values(
+    result: FileResult,
+    totals: &mut Totals,
+    rpc_values: &mut Vec<Value>,
+    errors: &mut Vec<Value>,
+) {
+    match result {
+        Ok((results, err)) => {
+            totals.files += 1;
+
+            let more = results.more();
+            let file = file_fields(&results);
+            let (path, results) = results.into_raw();
+            let matches = results
+                .iter()
+                .filter(|result| result.matched.is_some())
+                .count();
+            if matches > 0 {
+                totals.matched_files += 1;
+                totals.matches += matches as u64;
+            }
+
+            for result in results {
+                rpc_values.push(result_value(result, &file));
+            }
+            // The last result of the file tells how many matches follow it.
+            if more > 0 {
+                totals.more += more;
+                if let Some(Value::Map(last)) = rpc_values.last_mut() {
+                    last.push((Value::from("more"), Value::from(more)));
+                }
+            }
+            if let Some(e) = err {
+                totals.errors += 1;
+                errors.push(err_value(e, Some(&path)));
+            }
+        }
+        Err(e) => {
+            totals.errors += 1;
+            errors.push(err_value(e, None));
+        }
+    }
+}
+
+// Returns None if the file has neither matches nor errors.
+pub fn batch_value(result: FileResult, totals: &mut Totals) -> Option<Value> {
+    let mut rpc_values = Vec::new();
+    let mut errors = Vec::new();
+
+    file_values(result, totals, &mut rpc_values, &mut errors);
+
+    rpc_values.extend(errors);
+
+    if rpc_values.is_empty() {
+        None
+    } else {
+        Some(Value::Array(rpc_values))
+    }
+}
+
+// { truncated, cancelled, binary, binary_paths } at the end of the results, if matches were cut
+// at max_matches, the search was cancelled, or binary files were skipped.
+fn summary_value(cancelled: bool, truncated: bool, binary: SkippedBinary) -> Option<Value> {
+    if !truncated && !cancelled && binary.count == 0 {
+        return None;
+    }
+    let mut inner = vec![
+        (Value::from("truncated"), Value::from(truncated)),
+        (Value::from("cancelled"), Value::from(cancelled)),
+    ];
+    push_skipped_binary(&mut inner, binary);
+    Some(Value::Map(inner))
+}
+
+// Glob errors are appended after the file errors, followed by the summary if any.
+pub fn to_values(
+    search_results: impl Iterator<Item = FileResult>,
+    glob_errors: Vec<GlobError>,
+    cancelled: bool,
+    truncated: bool,
+    binary: SkippedBinary,
 ) -> Value {
+    let mut totals = Totals::default();
     let mut rpc_values = Vec::new();
     let mut errors = Vec::new();
 
     for result in search_results {
-        match result {
-            Ok((results, err)) => {
-                let (path, results) = results.into_raw();
-                for result in results {
-                    rpc_values.push(result_value(result, &path));
-                }
-                if let Some(e) = err {
-                    errors.push(err_value(e, Some(&path)));
-                }
-            }
-            Err(e) => errors.push(err_value(e, None)),
-        }
+        file_values(result, &mut totals, &mut rpc_values, &mut errors);
     }
 
     rpc_values.extend(errors);
+    rpc_values.extend(glob_errors.into_iter().map(glob_err_value));
+    rpc_values.extend(summary_value(cancelled, truncated, binary));
 
     Value::Array(rpc_values)
 }
+
+// { path, status, count, error, issues = [{ line, reason }] }; status is one of edited, unchanged,
+// conflict or failed.
+pub fn file_edit_value(edit: FileEdit) -> Value {
+    let mut inner = vec![(Value::from("path"), Value::from(edit.path))];
+
+    let status = match edit.status {
+        EditStatus::Edited => "edited",
+        EditStatus::Unchanged => "unchanged",
+        EditStatus::Conflict => "conflict",
+        EditStatus::Failed(e) => {
+            inner.push((Value::from("error"), Value::from(e)));
+            "failed"
+        }
+    };
+    inner.push((Value::from("status"), Value::from(status)));
+    inner.push((Value::from("count"), Value::from(edit.count)));
+
+    if !edit.issues.is_empty() {
+        let issues = edit
+            .issues
+            .into_iter()
+            .map(|(line, issue)| {
+                let reason = match issue {
+                    LineIssue::Stale => "stale",
+                    LineIssue::Missing => "missing",
+                    LineIssue::Conflict => "conflict",
+                };
+                Value::Map(vec![
+                    (Value::from("line"), Value::from(line)),
+                    (Value::from("reason"), Value::from(reason)),
+                ])
+            })
+            .collect();
+        inner.push((Value::from("issues"), Value::Array(issues)));
+    }
+
+    Value::Map(inner)
+}
+
+// Files are sent as a list of `{ path, hash }` pairs.
+pub fn file_hashes(items: &[Value]) -> Result<Vec<(&str, &str)>, Value> {
+    items
+        .iter()
+        .map(|item| match item.as_array().map(Vec::as_slice) {
+            Some([path, hash]) => match (path.as_str(), hash.as_str()) {
+                (Some(path), Some(hash)) => Ok((path, hash)),
+                _ => Err(msg_value(format!(
+                    "File path and hash must be strings: {item}"
+                ))),
+            },
+            _ => Err(msg_value(format!(
+                "File must be a {{ path, hash }} pair: {item}"
+            ))),
+        })
+        .collect()
+}
+
+// Options of diffs, only `context` for now, which is the number of unchanged lines around changes.
+pub fn diff_context(items: &[Value]) -> Result<usize, Value> {
+    let mut context = diff::DEFAULT_CONTEXT;
+    for (key, value) in option_pairs(items)? {
+        match key {
+            "context" => context = usize_option(key, value)?,
+            _ => return Err(msg_value(format!("Unknown option: {key}"))),
+        }
+    }
+    Ok(context)
+}
+
+// { files = [{ path, diff } or { path, error }], glob_errors = [glob errors], cancelled }
+pub fn diff_results_value(results: DiffResults) -> Value {
+    let files = results
+        .diffs
+        .into_iter()
+        .map(|file| {
+            let (key, value) = match file.diff {
+                Ok(diff) => ("diff", diff),
+                Err(e) => ("error", e.msg),
+            };
+            Value::Map(vec![
+                (Value::from("path"), Value::from(file.path)),
+                (Value::from(key), Value::from(value)),
+            ])
+        })
+        .collect();
+
+    Value::Map(vec![
+        (Value::from("files"), Value::Array(files)),
+        (
+            Value::from("glob_errors"),
+            glob_errs_value(results.glob_errors),
+        ),
+        (Value::from("cancelled"), Value::from(results.cancelled)),
+    ])
+}
+
+// { from, to, status, error }; status is one of ready, case_only, collision, exists or invalid when
+// planned, and one of renamed or failed when applied.
+pub fn rename_value(rename: Rename) -> Value {
+    let mut inner = vec![
+        (Value::from("from"), Value::from(rename.from)),
+        (Value::from("to"), Value::from(rename.to)),
+    ];
+
+    let status = match rename.status {
+        RenameStatus::Ready => "ready",
+        RenameStatus::CaseOnly => "case_only",
+        RenameStatus::Collision => "collision",
+        RenameStatus::Exists => "exists",
+        RenameStatus::Invalid => "invalid",
+        RenameStatus::Renamed => "renamed",
+        RenameStatus::Failed(e) => {
+            inner.push((Value::from("error"), Value::from(e)));
+            "failed"
+        }
+    };
+    inner.push((Value::from("status"), Value::from(status)));
+
+    Value::Map(inner)
+}
+
+// { renames = [renames], glob_errors = [glob errors] }
+pub fn rename_plan_value(plan: RenamePlan) -> Value {
+    let renames = plan.renames.into_iter().map(rename_value).collect();
+    Value::Map(vec![
+        (Value::from("renames"), Value::Array(renames)),
+        (
+            Value::from("glob_errors"),
+            glob_errs_value(plan.glob_errors),
+        ),
+    ])
+}
+
+// Renames are sent as a list of `{ from, to }` pairs.
+pub fn rename_pairs(items: &[Value]) -> Result<Vec<(String, String)>, Value> {
+    items
+        .iter()
+        .map(|item| match item.as_array().map(Vec::as_slice) {
+            Some([from, to]) => match (from.as_str(), to.as_str()) {
+                (Some(from), Some(to)) => Ok((from.to_string(), to.to_string())),
+                _ => Err(msg_value(format!("Rename paths must be strings: {item}"))),
+            },
+            _ => Err(msg_value(format!(
+                "Rename must be a {{ from, to }} pair: {item}"
+            ))),
+        })
+        .collect()
+}
+
+// Line edits are sent as a list of `{ path, line, original, new }`, and grouped by paths in the
+// order of their first appearances.
+pub fn line_edits(items: &[Value]) -> Result<Vec<(&str, Vec<LineEdit>)>, Value> {
+    let mut files: Vec<(&str, Vec<LineEdit>)> = Vec::new();
+
+    for item in items {
+        let Some([path, line, original, new]) = item.as_array().map(Vec::as_slice) else {
+            return Err(msg_value(format!(
+                "Line edit must be a {{ path, line, original, new }} list: {item}"
+            )));
+        };
+        let (Some(path), Some(line), Some(original), Some(new)) = (
+            path.as_str(),
+            line.as_u64(),
+            original.as_str(),
+            new.as_str(),
+        ) else {
+            return Err(msg_value(format!("Invalid line edit: {item}")));
+        };
+
+        let edit = LineEdit {
+            line,
+            original: original.to_string(),
+            new: new.to_string(),
+        };
+        match files.iter_mut().find(|(file, _)| *file == path) {
+            Some((_, edits)) => edits.push(edit),
+            None => files.push((path, vec![edit])),
+        }
+    }
+
+    Ok(files)
+}
+
+// { lines } of a member of an archive opened read-only, or { error, path }
+pub fn member_value(bytes: std::io::Result<Vec<u8>>, path: &str) -> Value {
+    match bytes {
+        Ok(bytes) => {
+            let lines = String::from_utf8_lossy(&bytes)
+                .lines()
+                .map(Value::from)
+                .collect();
+            Value::Map(vec![(Value::from("lines"), Value::Array(lines))])
+        }
+        Err(e) => err_value(RgErr { msg: e.to_string() }, Some(path)),
+    }
+}
+
+// Buffers are sent as a list of `{ name, lines }` pairs. Lines are taken as bytes, and joined as
+// written to the file.
+pub fn buffers(items: &[Value]) -> Buffers {
+    let mut buffers = Buffers::default();
+    for item in items {
+        let Some([name, Value::Array(lines)]) = item.as_array().map(Vec::as_slice) else {
+            continue;
+        };
+        let Some(name) = name.as_str() else {
+            continue;
+        };
+
+        let mut bytes = Vec::new();
+        for line in lines.iter().filter_map(Value::as_slice) {
+            bytes.extend_from_slice(line);
+            bytes.push(b'\n');
+        }
+        buffers.insert(name.as_ref(), bytes);
+    }
+    buffers
+}